@@ -0,0 +1,33 @@
+use clap::Parser;
+use zmq::{Context, REQ};
+
+use mite_controller_rust::controller::controller::{ControlCommand, ControlReply};
+
+/// Send a control command to a running controller and print its reply.
+///
+/// e.g. `mite-ctl '{"command": "set_multiplier", "multiplier": 2.0}'`
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Controller socket
+    #[arg(long, default_value = "tcp://127.0.0.1:14301")]
+    controller_socket: String,
+
+    /// The command as JSON
+    #[arg()]
+    command: String,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let command: ControlCommand = serde_json::from_str(&args.command)?;
+
+    let context = Context::new();
+    let socket = context.socket(REQ)?;
+    socket.connect(&args.controller_socket)?;
+    socket.send(rmp_serde::to_vec_named(&(4, command))?, 0)?;
+
+    let reply: ControlReply = rmp_serde::from_slice(&socket.recv_bytes(0)?)?;
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    Ok(())
+}
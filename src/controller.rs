@@ -1,3 +1,14 @@
+pub mod abort;
+#[allow(clippy::module_inception)]
 pub mod controller;
+pub mod report;
 pub mod runner_tracker;
+pub mod scenario_file;
 pub mod scenario_manager;
+pub mod schedule;
+pub mod scenario_source;
+pub mod timeline;
+pub mod token_bucket;
+pub mod transport;
+pub mod volume_evaluator;
+pub mod volume_model;
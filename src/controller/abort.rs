@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// When a test should be stopped early because it has gone badly. Every
+/// condition is off unless set.
+#[derive(Debug, Clone, Default)]
+pub struct AbortConditions {
+    /// Stop once no runner has been active for this many seconds.
+    pub no_runners_seconds: Option<u64>,
+    /// Stop once the achieved volume has been below this fraction of the
+    /// required volume for `underachieving_periods` periods in a row.
+    pub min_achieved_ratio: Option<f64>,
+    pub underachieving_periods: u32,
+    /// Stop once the error rate reported over the control socket exceeds this.
+    pub max_error_rate: Option<f64>,
+}
+
+/// What the controller knows at one check.
+pub struct AbortSample<'a> {
+    pub active_runners: usize,
+    pub required: &'a HashMap<i32, i32>,
+    pub actual: &'a HashMap<i32, i32>,
+    /// The period of scenario time volumes are currently required for.
+    pub period: (u64, u64),
+    /// Scenario time hasn't started or the test is paused, so nothing is
+    /// expected of the runners.
+    pub idle: bool,
+}
+
+/// Checks the abort conditions against the controller's view of the test.
+pub struct AbortMonitor {
+    conditions: AbortConditions,
+    last_check: Option<Instant>,
+    no_runners_since: Option<Instant>,
+    /// The period being sampled, with the required and achieved volumes
+    /// summed over every check during it.
+    period: (u64, u64),
+    required: f64,
+    achieved: f64,
+    /// Periods in a row the achieved volume fell short.
+    underachieving: u32,
+    error_rate: Option<f64>,
+}
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+impl AbortMonitor {
+    pub fn new(conditions: AbortConditions) -> Self {
+        Self {
+            conditions,
+            last_check: None,
+            no_runners_since: None,
+            period: (0, 0),
+            required: 0.0,
+            achieved: 0.0,
+            underachieving: 0,
+            error_rate: None,
+        }
+    }
+
+    /// Take in the latest error rate from an external monitor.
+    pub fn set_error_rate(&mut self, error_rate: f64) {
+        self.error_rate = Some(error_rate);
+    }
+
+    /// Whether it is time for another check; checks are cheap but there is
+    /// no point making one on every message.
+    pub fn due(&mut self) -> bool {
+        if self
+            .last_check
+            .is_some_and(|last_check| last_check.elapsed() < CHECK_INTERVAL)
+        {
+            return false;
+        }
+        self.last_check = Some(Instant::now());
+        true
+    }
+
+    /// Why the test should be stopped, if it should.
+    pub fn check(&mut self, sample: AbortSample) -> Option<String> {
+        if let (Some(max_error_rate), Some(error_rate)) =
+            (self.conditions.max_error_rate, self.error_rate)
+        {
+            if error_rate > max_error_rate {
+                return Some(format!(
+                    "error rate {} exceeded {}",
+                    error_rate, max_error_rate
+                ));
+            }
+        }
+        if sample.idle {
+            // nobody is expected to be running anything
+            self.no_runners_since = None;
+            self.underachieving = 0;
+            self.period = sample.period;
+            self.required = 0.0;
+            self.achieved = 0.0;
+            return None;
+        }
+        if let Some(reason) = self.check_runners(sample.active_runners) {
+            return Some(reason);
+        }
+        self.check_achieved(&sample)
+    }
+
+    fn check_runners(&mut self, active_runners: usize) -> Option<String> {
+        let no_runners_seconds = self.conditions.no_runners_seconds?;
+        if active_runners > 0 {
+            self.no_runners_since = None;
+            return None;
+        }
+        let no_runners_since = *self.no_runners_since.get_or_insert_with(Instant::now);
+        if no_runners_since.elapsed() >= Duration::from_secs(no_runners_seconds) {
+            return Some(format!(
+                "no active runners for {}s",
+                no_runners_since.elapsed().as_secs()
+            ));
+        }
+        None
+    }
+
+    /// Judge each period as it ends on the volumes sampled during it.
+    fn check_achieved(&mut self, sample: &AbortSample) -> Option<String> {
+        let min_achieved_ratio = self.conditions.min_achieved_ratio?;
+        if sample.period != self.period {
+            if self.required > 0.0 {
+                if self.achieved / self.required < min_achieved_ratio {
+                    self.underachieving += 1;
+                } else {
+                    self.underachieving = 0;
+                }
+            }
+            self.period = sample.period;
+            self.required = 0.0;
+            self.achieved = 0.0;
+            if self.underachieving >= self.conditions.underachieving_periods.max(1) {
+                return Some(format!(
+                    "achieved volume below {:.0}% of required for {} periods",
+                    min_achieved_ratio * 100.0,
+                    self.underachieving
+                ));
+            }
+        }
+        self.required += sample.required.values().sum::<i32>() as f64;
+        // one scenario running over can't make up for another falling short
+        self.achieved += sample
+            .required
+            .iter()
+            .map(|(scenario_id, required)| {
+                (*sample.actual.get(scenario_id).unwrap_or(&0)).min(*required) as f64
+            })
+            .sum::<f64>();
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample<'a>(
+        active_runners: usize,
+        required: &'a HashMap<i32, i32>,
+        actual: &'a HashMap<i32, i32>,
+        period: u64,
+    ) -> AbortSample<'a> {
+        AbortSample {
+            active_runners,
+            required,
+            actual,
+            period: (period, period + 1),
+            idle: false,
+        }
+    }
+
+    #[test]
+    fn no_runners_for_too_long() {
+        let mut monitor = AbortMonitor::new(AbortConditions {
+            no_runners_seconds: Some(5),
+            ..Default::default()
+        });
+        let none = HashMap::new();
+        assert_eq!(monitor.check(sample(0, &none, &none, 0)), None);
+        monitor.no_runners_since = Some(Instant::now() - Duration::from_secs(6));
+        assert!(monitor.check(sample(0, &none, &none, 0)).is_some());
+
+        // a runner turning up restarts the timer
+        assert_eq!(monitor.check(sample(1, &none, &none, 0)), None);
+        assert_eq!(monitor.no_runners_since, None);
+        assert_eq!(monitor.check(sample(0, &none, &none, 0)), None);
+    }
+
+    #[test]
+    fn idle_samples_reset_everything() {
+        let mut monitor = AbortMonitor::new(AbortConditions {
+            no_runners_seconds: Some(5),
+            min_achieved_ratio: Some(0.5),
+            underachieving_periods: 2,
+            ..Default::default()
+        });
+        let required = HashMap::from([(0, 10)]);
+        let none = HashMap::new();
+        monitor.check(sample(0, &required, &none, 0));
+        monitor.check(sample(0, &required, &none, 1));
+        assert_eq!(monitor.underachieving, 1);
+        assert!(monitor.no_runners_since.is_some());
+
+        let mut idle = sample(0, &required, &none, 2);
+        idle.idle = true;
+        assert_eq!(monitor.check(idle), None);
+        assert_eq!(monitor.underachieving, 0);
+        assert_eq!(monitor.no_runners_since, None);
+        assert_eq!((monitor.required, monitor.achieved), (0.0, 0.0));
+    }
+
+    #[test]
+    fn underachievement_is_judged_as_each_period_ends() {
+        let mut monitor = AbortMonitor::new(AbortConditions {
+            min_achieved_ratio: Some(0.6),
+            underachieving_periods: 3,
+            ..Default::default()
+        });
+        let required = HashMap::from([(0, 10), (1, 10)]);
+        let short = HashMap::from([(0, 2), (1, 2)]);
+        // one scenario running over doesn't make up for the other
+        let lopsided = HashMap::from([(0, 30), (1, 0)]);
+        let good = HashMap::from([(0, 8), (1, 8)]);
+
+        // nothing is judged until the period is over
+        for _ in 0..10 {
+            assert_eq!(monitor.check(sample(1, &required, &short, 0)), None);
+        }
+        assert_eq!(monitor.underachieving, 0);
+        assert_eq!(monitor.check(sample(1, &required, &lopsided, 1)), None);
+        assert_eq!(monitor.underachieving, 1);
+        assert_eq!(monitor.check(sample(1, &required, &good, 2)), None);
+        assert_eq!(monitor.underachieving, 2);
+        // a good period clears the count
+        assert_eq!(monitor.check(sample(1, &required, &short, 3)), None);
+        assert_eq!(monitor.underachieving, 0);
+        assert_eq!(monitor.check(sample(1, &required, &short, 4)), None);
+        assert_eq!(monitor.check(sample(1, &required, &short, 5)), None);
+        assert!(monitor.check(sample(1, &required, &short, 6)).is_some());
+    }
+
+    #[test]
+    fn error_rate_over_the_threshold() {
+        let mut monitor = AbortMonitor::new(AbortConditions {
+            max_error_rate: Some(0.1),
+            ..Default::default()
+        });
+        let none = HashMap::new();
+        assert_eq!(monitor.check(sample(1, &none, &none, 0)), None);
+        monitor.set_error_rate(0.1);
+        assert_eq!(monitor.check(sample(1, &none, &none, 0)), None);
+        monitor.set_error_rate(0.2);
+        let mut idle = sample(1, &none, &none, 0);
+        idle.idle = true;
+        assert!(monitor.check(idle).is_some());
+    }
+}
@@ -1,15 +1,36 @@
-use serde::Deserialize;
-use std::collections::HashMap;
-use zmq::{Context, DONTWAIT, REP};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGUSR1, SIGUSR2};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use super::runner_tracker::RunnerTracker;
-use super::scenario_manager::ScenarioManager;
+use super::abort::{AbortConditions, AbortMonitor, AbortSample};
+use super::report::TestReport;
+use super::runner_tracker::{RunnerCapacity, RunnerRecord, RunnerTracker};
+use super::scenario_file::ScenarioEntry;
+use super::scenario_manager::{
+    PhaseChange, Placement, RampLimits, ReloadReport, RunnerRequest, ScenarioDefinition,
+    ScenarioManager, Work,
+};
+use super::scenario_source::{self, JsonSource, ScenarioSource};
+use super::timeline::PeriodVolume;
+use super::token_bucket::SpawnMetrics;
+use super::transport::{MessageSender, Transport, ZmqTransport};
+use super::volume_model::{Datapool, NativeVolumeModel, VolumeModel};
 
 pub struct WorkTracker {
     all_work: HashMap<i32, HashMap<i32, i32>>,
     total_work: HashMap<i32, i32>,
 }
 
+impl Default for WorkTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WorkTracker {
     pub fn new() -> Self {
         Self {
@@ -26,7 +47,7 @@ impl WorkTracker {
         //     self._total_work[k] -= v
         if let Some(runner_work) = runner_work {
             for (k, v) in runner_work.iter() {
-                match self.total_work.get_mut(&k) {
+                match self.total_work.get_mut(k) {
                     Some(total_work) => *total_work -= v,
                     None => {
                         println!("Key not found in total_work, adding key: {}", k);
@@ -39,7 +60,7 @@ impl WorkTracker {
         // for k, v in work.items():
         //     self._total_work[k] += v
         for (k, v) in work.iter() {
-            match self.total_work.get_mut(&k) {
+            match self.total_work.get_mut(k) {
                 Some(total_work) => *total_work += v,
                 None => {
                     println!("Key not found in total_work, adding key: {}", k);
@@ -48,7 +69,7 @@ impl WorkTracker {
             }
 
             // println!("add work - k: {}, v: {}", k, v);
-            // let total_work = self.total_work.get_mut(&k).unwrap();
+            // let total_work = self.total_work.get_mut(k).unwrap();
             // *total_work += v;
         }
         // self._all_work[runner_id] = defaultdict(int, work)
@@ -63,39 +84,94 @@ impl WorkTracker {
             if current.contains_key(k) {
                 // let volume = scenario_volume_map.get(scenario_id).unwrap();
                 // scenario_volume_map.insert(scenario_id.clone(), volume + 1);
-                let current_work = current.get_mut(&k).unwrap();
+                let current_work = current.get_mut(k).unwrap();
                 *current_work += v;
             } else {
-                current.insert(k.clone(), v.clone());
+                current.insert(*k, *v);
             }
 
             if self.total_work.contains_key(k) {
-                let total_work = self.total_work.get_mut(&k).unwrap();
+                let total_work = self.total_work.get_mut(k).unwrap();
                 *total_work += v;
             } else {
-                self.total_work.insert(k.clone(), v.clone());
+                self.total_work.insert(*k, *v);
             }
         }
     }
+
+    pub fn remove_assumed(&mut self, runner_id: i32, work: &HashMap<i32, i32>) {
+        let current = self.all_work.entry(runner_id).or_default();
+        for (k, v) in work.iter() {
+            if let Some(current_work) = current.get_mut(k) {
+                *current_work = (*current_work - v).max(0);
+            }
+            if let Some(total_work) = self.total_work.get_mut(k) {
+                *total_work = (*total_work - v).max(0);
+            }
+        }
+    }
+
+    /// Forget a runner's work, e.g. once it has said bye or restarted.
+    pub fn remove_runner(&mut self, runner_id: i32) {
+        self.set_actual(runner_id, HashMap::new());
+        self.all_work.remove(&runner_id);
+    }
+
+    pub fn get_runner_work(&self, runner_id: i32) -> HashMap<i32, i32> {
+        self.all_work.get(&runner_id).cloned().unwrap_or_default()
+    }
 }
 
+/// A request_work reply: work, config, whether to stop, and the work to shed
+/// for runners that accept reductions.
+pub type WorkReply<'a> = (Work, HashMap<&'a str, &'a str>, bool, Option<HashMap<i32, i32>>);
+
 pub struct Controller {
     scenario_spec: String,
     message_socket: String,
     controller_socket: String,
     scenario_manager: ScenarioManager,
+    /// Where announcements go, once the server is running.
+    messages: Option<MessageSender>,
+    abort_monitor: AbortMonitor,
+    /// Why the test was stopped early, if it was.
+    abort_reason: Option<String>,
     work_tracker: WorkTracker,
     runner_tracker: RunnerTracker,
-    runner_count: u64,
+    runner_id_gen: i32,
+    start_time: u64,
+    exclude_paused_time: bool,
+    pause_signal: Arc<AtomicBool>,
+    resume_signal: Arc<AtomicBool>,
     debug: bool,
 }
 
-#[derive(Deserialize)]
-struct MessageData {
-    runner_id: i32,
-    current_work: HashMap<i32, i32>,
-    completed_data_ids: Vec<Option<(i32, i32)>>,
-    max_work: Option<i32>,
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageData {
+    pub runner_id: i32,
+    pub current_work: HashMap<i32, i32>,
+    pub completed_data_ids: Vec<Option<(i32, i32)>>,
+    pub max_work: Option<i32>,
+    /// Journey errors since the runner's previous request.
+    #[serde(default)]
+    pub errors: i64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct HelloData {
+    /// Stable across restarts of the same runner, e.g. hostname and pid or a
+    /// uuid the runner persists, so a reconnect keeps its runner id. Must be
+    /// unique to one runner: see `Controller::hello`.
+    #[serde(default)]
+    pub identity: Option<String>,
+    /// The runner understands the reductions element of the request_work
+    /// reply. Runners that don't say so get the original three-element reply.
+    #[serde(default)]
+    pub reductions: bool,
+    #[serde(flatten)]
+    pub capacity: RunnerCapacity,
 }
 
 enum MessageType {
@@ -109,6 +185,112 @@ struct Message {
     message: Option<MessageData>,
 }
 
+/// Operations on a running test, sent as `(4, command)` on the controller socket.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    Status,
+    RunnerStats,
+    Pause,
+    Resume {
+        /// Leave the paused interval out of scenario time. Defaults to
+        /// --exclude-paused-time.
+        #[serde(default)]
+        exclude_paused: Option<bool>,
+    },
+    SetMultiplier {
+        /// Every scenario if unset.
+        #[serde(default)]
+        scenario_id: Option<i32>,
+        multiplier: f64,
+    },
+    /// Re-read the scenario generator or file the test was started with.
+    Reload,
+    /// Run an extra scenario alongside the ones the test was started with.
+    AddScenario {
+        journey_spec: String,
+        /// Passed through to the runners by name.
+        #[serde(default)]
+        datapool: Option<String>,
+        volume_model: NativeVolumeModel,
+        /// Seconds the scenario runs for, if limited.
+        #[serde(default)]
+        duration: Option<u64>,
+        #[serde(default)]
+        placement: Placement,
+        #[serde(default)]
+        ramp: RampLimits,
+    },
+    RemoveScenario {
+        scenario_id: i32,
+    },
+    /// Add the scenarios from another scenario spec such as
+    /// `file:overlay.toml`, or given inline in the scenario file shape.
+    LoadScenarios {
+        #[serde(default)]
+        scenario_spec: Option<String>,
+        #[serde(default)]
+        scenarios: Vec<ScenarioEntry>,
+    },
+    /// Feed in an error rate from outside the controller, e.g. a monitoring
+    /// system, to be checked against --abort-error-rate.
+    ReportErrorRate {
+        error_rate: f64,
+    },
+    /// Required volume per period, achieved volume for periods gone by, and
+    /// the periods evaluated ahead.
+    Timeline {
+        /// Only periods ending after this scenario time.
+        #[serde(default)]
+        since: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum ControlReply {
+    Status(Status),
+    RunnerStats { runners: HashMap<i32, RunnerRecord> },
+    Reloaded(ReloadReport),
+    ScenarioAdded { scenario_id: i32 },
+    ScenarioRemoved { scenario_id: i32 },
+    ScenariosLoaded { scenario_ids: Vec<i32> },
+    Timeline {
+        scenario_time: u64,
+        scenarios: BTreeMap<i32, Vec<PeriodVolume>>,
+    },
+    Error { message: String },
+}
+
+/// Events announced on the message socket, as maps tagged with `type`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Announcement {
+    PhaseChange(PhaseChange),
+}
+
+/// Snapshot of how the work handed out compares to the work required.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub scenario_time: u64,
+    pub active: bool,
+    /// Scenario time has yet to start; runners may connect but get no work.
+    pub waiting: bool,
+    /// Seconds until scenario time starts, while waiting.
+    pub starts_in: Option<u64>,
+    /// The test phase running now, if the test has phases.
+    pub phase: Option<String>,
+    pub paused: bool,
+    pub active_runners: usize,
+    pub required: HashMap<i32, i32>,
+    pub actual: HashMap<i32, i32>,
+    /// Runner requests per second.
+    pub hit_rate: f64,
+    pub spawn: SpawnMetrics,
+    pub global_multiplier: f64,
+    pub scenario_multipliers: HashMap<i32, f64>,
+}
+
 // static MESSAGE_TYPES: [MessageType; 4] = [
 //     MessageType::Heartbeat,
 //     MessageType::Hello,
@@ -116,6 +298,17 @@ struct Message {
 //     MessageType::Bye,
 // ];
 
+fn decode<T: DeserializeOwned>(msg: &[u8]) -> Option<T> {
+    match rmp_serde::from_slice(msg) {
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            eprintln!("Failed to parse message: {}", e);
+            println!("Message: {:?}", msg);
+            None
+        }
+    }
+}
+
 impl Controller {
     pub fn new(
         scenario_spec: String,
@@ -129,44 +322,163 @@ impl Controller {
             message_socket,
             controller_socket,
             scenario_manager,
+            messages: None,
+            abort_monitor: AbortMonitor::new(AbortConditions::default()),
+            abort_reason: None,
             work_tracker: WorkTracker::new(),
             runner_tracker: RunnerTracker::new(10, debug),
-            runner_count: 0,
+            runner_id_gen: 0,
+            start_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            exclude_paused_time: false,
+            pause_signal: Arc::new(AtomicBool::new(false)),
+            resume_signal: Arc::new(AtomicBool::new(false)),
             debug,
         }
     }
 
-    pub fn hello(&mut self) -> u64 {
-        self.runner_count += 1;
-        self.runner_count
+    pub fn set_hit_rate_window(&mut self, window: u64) {
+        self.runner_tracker.set_hit_rate_window(window);
+    }
+
+    /// Whether resuming leaves the paused interval out of scenario time when
+    /// the request doesn't say.
+    pub fn set_exclude_paused_time(&mut self, exclude_paused_time: bool) {
+        self.exclude_paused_time = exclude_paused_time;
     }
 
-    pub fn get_runner_count(&self) -> u64 {
-        self.runner_count
+    /// Stop the test early when any of `conditions` is met.
+    pub fn set_abort_conditions(&mut self, conditions: AbortConditions) {
+        self.abort_monitor = AbortMonitor::new(conditions);
+    }
+
+    /// Stop the test if it has gone badly enough to meet an abort condition.
+    fn check_abort(&mut self) {
+        if self.abort_reason.is_some() || !self.abort_monitor.due() {
+            return;
+        }
+        let required = self.scenario_manager.get_required();
+        let sample = AbortSample {
+            active_runners: self.get_runner_count(),
+            required: &required,
+            actual: &self.work_tracker.total_work,
+            period: self.scenario_manager.current_period(),
+            idle: self.scenario_manager.is_waiting() || self.scenario_manager.is_paused(),
+        };
+        if let Some(reason) = self.abort_monitor.check(sample) {
+            println!("Aborting test: {}", reason);
+            self.scenario_manager.stop();
+            self.abort_reason = Some(reason);
+        }
+    }
+
+    /// Pause on SIGUSR1 and resume on SIGUSR2.
+    pub fn watch_signals(&self) -> Result<(), std::io::Error> {
+        signal_hook::flag::register(SIGUSR1, Arc::clone(&self.pause_signal))?;
+        signal_hook::flag::register(SIGUSR2, Arc::clone(&self.resume_signal))?;
+        Ok(())
+    }
+
+    fn check_signals(&mut self) {
+        if self.pause_signal.swap(false, Ordering::Relaxed) {
+            self.scenario_manager.pause();
+        }
+        if self.resume_signal.swap(false, Ordering::Relaxed) {
+            self.scenario_manager.resume(self.exclude_paused_time);
+        }
+    }
+
+    /// Register a runner and give it its id. A hello carrying an identity
+    /// already known is taken as that runner having restarted, even if it
+    /// still looks live, since a quick restart can't be told apart from a
+    /// second runner claiming the same identity: it keeps the id and its old
+    /// work is forgotten.
+    pub fn hello(&mut self, hello_data: HelloData) -> i32 {
+        let reconnected = hello_data
+            .identity
+            .as_ref()
+            .and_then(|identity| self.runner_tracker.find_identity(identity));
+        let runner_id = match reconnected {
+            Some(runner_id) => {
+                // the runner restarted, so whatever it was running went with it
+                self.work_tracker.remove_runner(runner_id);
+                println!(
+                    "Runner {} reconnected, reusing id: {}",
+                    hello_data.identity.as_deref().unwrap_or_default(),
+                    runner_id
+                );
+                runner_id
+            }
+            None => {
+                self.runner_id_gen += 1;
+                self.runner_id_gen
+            }
+        };
+        self.runner_tracker.register(
+            runner_id,
+            hello_data.identity,
+            hello_data.reductions,
+            hello_data.capacity,
+        );
+        runner_id
+    }
+
+    pub fn bye(&mut self, runner_id: i32) {
+        self.runner_tracker.remove(runner_id);
+        self.work_tracker.remove_runner(runner_id);
+    }
+
+    /// Runners that have said hello, not said bye, and been seen recently.
+    pub fn get_runner_count(&self) -> usize {
+        self.runner_tracker.get_active().len()
     }
 
     pub fn required_work_for_runner(
         &mut self,
         runner_id: i32,
         max_work: Option<i32>,
-    ) -> Vec<(i32, i32, String, String)> {
-        let runner_total = match self.work_tracker.total_work.get(&runner_id) {
-            Some(runner_total) => *runner_total,
-            None => 0,
-        };
-        let active_runner_ids = self.runner_tracker.get_active();
+    ) -> (Work, Option<HashMap<i32, i32>>) {
+        let runner_work = self.work_tracker.get_runner_work(runner_id);
+        let runner_total: i32 = runner_work.values().sum();
         let current_work = self.work_tracker.total_work.clone();
-        let hit_rate = self.runner_tracker.get_hit_rate();
-        let (work, scenario_volume_map) = self.scenario_manager.get_work(
-            current_work,
-            runner_total,
-            active_runner_ids.len() as i32,
-            max_work,
-            hit_rate,
-        );
+        let capacity = self.runner_tracker.get_capacity(runner_id);
+        let max_work = match capacity.max_concurrent {
+            Some(max_concurrent) => {
+                let headroom = (max_concurrent - runner_total).max(0);
+                Some(max_work.map_or(headroom, |max_work| max_work.min(headroom)))
+            }
+            None => max_work,
+        };
+        let runner = RunnerRequest {
+            work: runner_work,
+            weight: capacity.weight(),
+            fleet: self.runner_tracker.get_fleet(),
+            self_limit: max_work,
+            labels: capacity.labels,
+        };
+        let (work, scenario_volume_map) =
+            self.scenario_manager.get_work(current_work, &runner);
+        // runners that can't be told to shed work are left to finish it
+        let reductions = self
+            .runner_tracker
+            .accepts_reductions(runner_id)
+            .then(|| {
+                self.scenario_manager.get_reductions(
+                    &self.work_tracker.total_work,
+                    &self.work_tracker.get_runner_work(runner_id),
+                )
+            });
+        if let Some(reductions) = &reductions {
+            if self.debug && !reductions.is_empty() {
+                println!("Reducing work for runner {}: {:?}", runner_id, reductions);
+            }
+            self.work_tracker.remove_assumed(runner_id, reductions);
+        }
         self.work_tracker
             .add_assumed(runner_id, scenario_volume_map);
-        work
+        (work, reductions)
     }
 
     pub fn request_work(
@@ -175,13 +487,25 @@ impl Controller {
         current_work: HashMap<i32, i32>,
         completed_data_ids: Vec<Option<(i32, i32)>>,
         max_work: Option<i32>,
-    ) -> (Vec<(i32, i32, String, String)>, HashMap<&str, &str>, bool) {
+        errors: i64,
+    ) -> WorkReply<'static> {
+        // whatever we assumed the runner was running and it no longer is has completed
+        let assumed: i32 = self.work_tracker.get_runner_work(runner_id).values().sum();
+        let actual: i32 = current_work.values().sum();
+        let completed = (assumed - actual).max(0);
+
+        self.runner_tracker.update(runner_id, &current_work, errors);
         self.work_tracker.set_actual(runner_id, current_work);
-        self.runner_tracker.update(runner_id);
         self.scenario_manager
-            .checkin_data(completed_data_ids.iter().map(|x| x.unwrap()).collect());
+            .checkin_data(completed_data_ids.iter().flatten().copied().collect());
 
-        let work = self.required_work_for_runner(runner_id, max_work);
+        let (work, reductions) = self.required_work_for_runner(runner_id, max_work);
+        self.runner_tracker.record_work(
+            runner_id,
+            work.len() as i64,
+            reductions.as_ref().map_or(0, |reductions| reductions.values().sum::<i32>()) as i64,
+            completed as i64,
+        );
 
         // println!("request_work - {} {:?}", runner_id, work);
 
@@ -191,101 +515,515 @@ impl Controller {
         config.insert("k", "v");
 
         let scenario_is_active = self.scenario_manager.is_active();
-        (work, config, !scenario_is_active)
+        (work, config, !scenario_is_active, reductions)
     }
 
-    pub fn run_server(&mut self) {
-        let zmq_context = Context::new();
-        let socket = zmq_context.socket(REP).unwrap();
-        match socket.bind(&self.controller_socket) {
-            Ok(_) => {
+    pub fn status(&mut self) -> Status {
+        let (global_multiplier, scenario_multipliers) = self.scenario_manager.get_multipliers();
+        Status {
+            scenario_time: self.scenario_manager._now(),
+            active: self.scenario_manager.is_active(),
+            waiting: self.scenario_manager.is_waiting(),
+            starts_in: self.scenario_manager.starts_in(),
+            phase: self.scenario_manager.current_phase(),
+            paused: self.scenario_manager.is_paused(),
+            active_runners: self.get_runner_count(),
+            required: self.scenario_manager.get_required(),
+            actual: self.work_tracker.total_work.clone(),
+            hit_rate: self.runner_tracker.get_hit_rate(),
+            spawn: self.scenario_manager.get_spawn_metrics(),
+            global_multiplier,
+            scenario_multipliers,
+        }
+    }
+
+    pub fn control(&mut self, command: ControlCommand) -> ControlReply {
+        match command {
+            ControlCommand::Status => ControlReply::Status(self.status()),
+            ControlCommand::RunnerStats => ControlReply::RunnerStats {
+                runners: self.runner_tracker.get_records(),
+            },
+            ControlCommand::Pause => {
+                self.scenario_manager.pause();
+                ControlReply::Status(self.status())
+            }
+            ControlCommand::Resume { exclude_paused } => {
+                self.scenario_manager
+                    .resume(exclude_paused.unwrap_or(self.exclude_paused_time));
+                ControlReply::Status(self.status())
+            }
+            ControlCommand::SetMultiplier {
+                scenario_id,
+                multiplier,
+            } => {
+                match self.scenario_manager.set_multiplier(scenario_id, multiplier) {
+                    Ok(()) => ControlReply::Status(self.status()),
+                    Err(message) => ControlReply::Error { message },
+                }
+            }
+            ControlCommand::Reload => match scenario_source::from_spec(&self.scenario_spec)
+                .and_then(|source| self.scenario_manager.reload(source.as_ref()))
+            {
+                Ok(report) => ControlReply::Reloaded(report),
+                Err(e) => ControlReply::Error {
+                    message: format!("Failed to reload {}: {}", self.scenario_spec, e),
+                },
+            },
+            ControlCommand::AddScenario {
+                journey_spec,
+                datapool,
+                volume_model,
+                duration,
+                placement,
+                ramp,
+            } => {
+                let scenario_id = self.scenario_manager.add_scenario(ScenarioDefinition {
+                    journey_spec,
+                    datapool: datapool.map(Datapool::Named),
+                    volumemodel: VolumeModel::Native(volume_model),
+                    duration,
+                    placement,
+                    ramp,
+                });
+                self.scenario_manager.refresh_required();
+                ControlReply::ScenarioAdded { scenario_id }
+            }
+            ControlCommand::RemoveScenario { scenario_id } => {
+                if self.scenario_manager.remove_scenario(scenario_id) {
+                    ControlReply::ScenarioRemoved { scenario_id }
+                } else {
+                    ControlReply::Error {
+                        message: format!("No scenario with id {}", scenario_id),
+                    }
+                }
+            }
+            ControlCommand::LoadScenarios {
+                scenario_spec,
+                scenarios,
+            } => {
+                let source: Result<Box<dyn ScenarioSource>, _> = match &scenario_spec {
+                    Some(scenario_spec) => scenario_source::from_spec(scenario_spec),
+                    None => Ok(Box::new(JsonSource::new(scenarios))),
+                };
+                let loaded =
+                    source.and_then(|source| self.scenario_manager.load_scenarios(source.as_ref()));
+                match loaded {
+                    Ok(scenario_ids) => {
+                        self.scenario_manager.refresh_required();
+                        ControlReply::ScenariosLoaded { scenario_ids }
+                    }
+                    Err(e) => ControlReply::Error {
+                        message: format!("Failed to load scenarios: {}", e),
+                    },
+                }
+            }
+            ControlCommand::ReportErrorRate { error_rate } => {
+                self.abort_monitor.set_error_rate(error_rate);
+                ControlReply::Status(self.status())
+            }
+            ControlCommand::Timeline { since } => ControlReply::Timeline {
+                scenario_time: self.scenario_manager._now(),
+                scenarios: self.scenario_manager.get_timeline(since.unwrap_or(0)),
+            },
+        }
+    }
+
+    /// Decode a single runner request and build the reply to send back.
+    /// Every request gets a reply, since a REP socket can't take the next
+    /// request until the last is answered; requests that can't be handled
+    /// get an error reply.
+    pub fn handle_message(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.reply(msg).unwrap_or_else(|| {
+            let error = ControlReply::Error {
+                message: "Request could not be handled".to_string(),
+            };
+            rmp_serde::to_vec_named(&error).unwrap()
+        })
+    }
+
+    /// The reply to `msg`, or `None` if it can't be handled.
+    fn reply(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        // the payload's shape depends on the message type, so peek at that first
+        let (message_type, _): (i32, IgnoredAny) = decode(msg)?;
+
+        let message = match message_type {
+            0 => Message {
+                message_type: MessageType::Heartbeat,
+                message: decode::<(i32, Option<MessageData>)>(msg)?.1,
+            },
+            1 => Message {
+                message_type: MessageType::Hello,
+                message: None,
+            },
+            2 => Message {
+                message_type: MessageType::RequestWork,
+                message: decode::<(i32, Option<MessageData>)>(msg)?.1,
+            },
+            3 => Message {
+                message_type: MessageType::Bye,
+                message: decode::<(i32, Option<MessageData>)>(msg)?.1,
+            },
+            4 => {
+                let (_, command): (i32, ControlCommand) = decode(msg)?;
                 if self.debug {
-                    println!("binding to {}", self.controller_socket);
+                    println!("Control command received: {:?}", command);
                 }
+                return Some(rmp_serde::to_vec_named(&self.control(command)).unwrap());
             }
-            Err(e) => panic!("Failed to bind to socket {}: {}", self.controller_socket, e),
+            _ => return None,
+        };
+
+        let reply = match message.message_type {
+            MessageType::Hello => {
+                let hello_data = decode::<(i32, Option<HelloData>)>(msg)
+                    .and_then(|(_, hello_data)| hello_data)
+                    .unwrap_or_default();
+                let runner_id = self.hello(hello_data);
+                if self.debug {
+                    println!("Hello received");
+                    println!("Adding Runner id: {}", runner_id);
+                    println!("Total Runner count: {}\n", self.get_runner_count());
+                }
+
+                // [TODO] correct config
+                let mut config = HashMap::new();
+                config.insert("k", "v");
+
+                let hello_resp = (runner_id, self.scenario_spec.to_string(), config);
+                rmp_serde::to_vec(&hello_resp).unwrap()
+            }
+            MessageType::Heartbeat => {
+                if let Some(message_data) = message.message {
+                    self.runner_tracker.touch(message_data.runner_id);
+                }
+                println!("Heartbeat received");
+                rmp_serde::to_vec(&()).unwrap()
+            }
+            MessageType::RequestWork => {
+                let message_data = message.message?;
+                let runner_id = message_data.runner_id;
+                let current_work = message_data.current_work;
+                let completed_data_ids = message_data.completed_data_ids;
+                let max_work = message_data.max_work;
+                let errors = message_data.errors;
+
+                let (work, config, stop, reductions) = self.request_work(
+                    runner_id,
+                    current_work,
+                    completed_data_ids,
+                    max_work,
+                    errors,
+                );
+                match reductions {
+                    Some(reductions) => {
+                        rmp_serde::to_vec(&(work, config, stop, reductions)).unwrap()
+                    }
+                    None => rmp_serde::to_vec(&(work, config, stop)).unwrap(),
+                }
+            }
+            MessageType::Bye => {
+                if let Some(message_data) = message.message {
+                    self.bye(message_data.runner_id);
+                }
+                println!("Bye received");
+                rmp_serde::to_vec(&()).unwrap()
+            }
+        };
+        Some(reply)
+    }
+
+    /// The scenario has finished and every runner has gone away.
+    pub fn is_finished(&self) -> bool {
+        !self.scenario_manager.is_active() && self.get_runner_count() == 0
+    }
+
+    pub fn report(&self) -> TestReport {
+        TestReport {
+            scenario_spec: self.scenario_spec.clone(),
+            start_time: self.start_time,
+            end_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            runners: self.runner_tracker.get_records().into_iter().collect(),
+            multiplier_changes: self.scenario_manager.get_multiplier_changes(),
+            timeline: self.scenario_manager.get_history().scenarios,
+            phase_changes: self.scenario_manager.get_phase_changes(),
+            abort_reason: self.abort_reason.clone(),
         }
+    }
+
+    /// Send any phase changes since the last call to the message socket.
+    fn announce(&mut self) {
+        for change in self.scenario_manager.take_phase_changes() {
+            if let Some(messages) = self.messages.as_ref() {
+                let announcement = Announcement::PhaseChange(change);
+                messages.send(&rmp_serde::to_vec_named(&announcement).unwrap());
+            }
+        }
+    }
 
-        loop {
-            let msg: Vec<u8> = match socket.recv_bytes(DONTWAIT) {
-                Ok(msg) => msg,
-                Err(_) => {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
+    /// Answer runner requests arriving on `transport` until it is closed or
+    /// the test is finished.
+    pub fn serve<T: Transport>(&mut self, transport: &mut T) {
+        while !transport.is_closed() && !self.is_finished() {
+            self.check_signals();
+            // deadlines and phase changes don't wait for a runner to ask for work
+            self.scenario_manager.advance();
+            self.announce();
+            self.check_abort();
+            let msg = match transport.recv() {
+                Some(msg) => msg,
+                None => {
+                    std::thread::sleep(IDLE_POLL_INTERVAL);
                     continue;
                 }
             };
-            let msg: (i32, Option<MessageData>) = match rmp_serde::from_slice(&msg) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    eprintln!("Failed to parse message: {}", e);
-                    println!("Message: {:?}", msg);
-                    continue;
+            let reply = self.handle_message(&msg);
+            transport.send(&reply);
+            self.announce();
+        }
+    }
+
+    pub fn run_server(&mut self) {
+        let mut transport = match ZmqTransport::bind(&self.controller_socket) {
+            Ok(transport) => {
+                if self.debug {
+                    println!("binding to {}", self.controller_socket);
+                    println!("message socket: {}", self.message_socket);
                 }
-            };
+                transport
+            }
+            Err(e) => panic!("Failed to bind to socket {}: {}", self.controller_socket, e),
+        };
+        match MessageSender::connect(&self.message_socket) {
+            Ok(messages) => self.messages = Some(messages),
+            Err(e) => eprintln!(
+                "Failed to connect to message socket {}: {}",
+                self.message_socket, e
+            ),
+        }
+        self.serve(&mut transport);
+    }
+}
 
-            let message = match msg.0 {
-                0 => Message {
-                    message_type: MessageType::Heartbeat,
-                    message: msg.1,
-                },
-                1 => Message {
-                    message_type: MessageType::Hello,
-                    message: None,
-                },
-                2 => Message {
-                    message_type: MessageType::RequestWork,
-                    message: msg.1,
-                },
-                3 => Message {
-                    message_type: MessageType::Bye,
-                    message: msg.1,
-                },
-                _ => continue,
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::transport::{ChannelClient, ChannelTransport};
+    use std::thread;
+    use std::time::Instant;
 
-            match message.message_type {
-                MessageType::Hello => {
-                    let runner_id = self.hello();
-                    if self.debug {
-                        println!("Hello received");
-                        println!("Adding Runner id: {}", runner_id);
-                        println!("Total Runner count: {}\n", self.get_runner_count());
-                    }
+    type Config = HashMap<String, String>;
 
-                    // [TODO] correct config
-                    let mut config = HashMap::new();
-                    config.insert("k", "v");
+    fn request<T: DeserializeOwned>(client: &ChannelClient, message: &impl Serialize) -> T {
+        let reply = client
+            .request(rmp_serde::to_vec_named(message).unwrap())
+            .unwrap();
+        rmp_serde::from_slice(&reply).unwrap()
+    }
 
-                    let hello_resp = (runner_id, self.scenario_spec.to_string(), config);
-                    let buf = rmp_serde::to_vec(&hello_resp).unwrap();
-                    socket.send(&buf, 0).unwrap();
-                }
-                MessageType::Heartbeat => {
-                    // [TODO] update runner tracker
-                    println!("Heartbeat received");
-                    // socket.send("Heartbeat received".as_bytes(), 0).unwrap();
+    fn hello(client: &ChannelClient, reductions: bool) -> i32 {
+        let hello_data = HelloData {
+            reductions,
+            ..Default::default()
+        };
+        let (runner_id, _, _): (i32, String, Config) = request(client, &(1, hello_data));
+        runner_id
+    }
+
+    fn message(runner_id: i32, current_work: &HashMap<i32, i32>) -> MessageData {
+        MessageData {
+            runner_id,
+            current_work: current_work.clone(),
+            completed_data_ids: vec![],
+            max_work: None,
+            errors: 0,
+        }
+    }
+
+    fn controller(scenarios: &str) -> Controller {
+        let mut scenario_manager = ScenarioManager::new(1, 0, 1000, false);
+        let source = JsonSource::parse(scenarios).unwrap();
+        scenario_manager.load_spec(&source).unwrap();
+        Controller::new(
+            format!("json:{}", scenarios),
+            String::new(),
+            String::new(),
+            scenario_manager,
+            false,
+        )
+    }
+
+    /// Serve `controller` in-process to `runner`, run on a thread of its own,
+    /// until the test finishes; returns what the runner returns.
+    fn serve<R: Send>(
+        controller: &mut Controller,
+        runner: impl FnOnce(ChannelClient) -> R + Send,
+    ) -> R {
+        let (mut transport, client) = ChannelTransport::pair();
+        thread::scope(|scope| {
+            let handle = scope.spawn(move || runner(client));
+            controller.serve(&mut transport);
+            // unblocks the runner if it is still waiting on a reply
+            drop(transport);
+            handle.join().unwrap()
+        })
+    }
+
+    #[test]
+    fn answers_requests_it_cannot_handle() {
+        let endpoint = format!("ipc:///tmp/mite-controller-test-{}", std::process::id());
+        let mut transport = ZmqTransport::bind(&endpoint).unwrap();
+        let mut controller = controller(r#"{"scenario": []}"#);
+        let bad_requests = vec![
+            b"garbage".to_vec(),
+            rmp_serde::to_vec(&(9, ())).unwrap(),
+            rmp_serde::to_vec(&(2, None::<MessageData>)).unwrap(),
+        ];
+        let requests = bad_requests.len() + 1;
+        let runner = thread::spawn(move || {
+            let context = zmq::Context::new();
+            let socket = context.socket(zmq::REQ).unwrap();
+            socket.connect(&endpoint).unwrap();
+            for request in bad_requests {
+                socket.send(request, 0).unwrap();
+                let reply: ControlReply =
+                    rmp_serde::from_slice(&socket.recv_bytes(0).unwrap()).unwrap();
+                assert!(matches!(reply, ControlReply::Error { .. }));
+            }
+            let hello = rmp_serde::to_vec_named(&(1, HelloData::default())).unwrap();
+            socket.send(hello, 0).unwrap();
+            let (runner_id, _, _): (i32, String, Config) =
+                rmp_serde::from_slice(&socket.recv_bytes(0).unwrap()).unwrap();
+            runner_id
+        });
+
+        for _ in 0..requests {
+            let msg = loop {
+                match transport.recv() {
+                    Some(msg) => break msg,
+                    None => thread::sleep(IDLE_POLL_INTERVAL),
                 }
-                MessageType::RequestWork => {
-                    let message_data = message.message.unwrap();
-                    let runner_id = message_data.runner_id;
-                    let current_work = message_data.current_work;
-                    let completed_data_ids = message_data.completed_data_ids;
-                    let max_work = message_data.max_work;
-
-                    let work_resp =
-                        self.request_work(runner_id, current_work, completed_data_ids, max_work);
-                    let buf = rmp_serde::to_vec(&work_resp).unwrap();
-                    socket.send(&buf, 0).unwrap();
+            };
+            transport.send(&controller.handle_message(&msg));
+        }
+        assert_eq!(runner.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn max_duration_ends_the_test_without_runners() {
+        let mut controller = controller(
+            r#"{"scenario": [{"journey": "t:j", "volume_model":
+                {"type": "constant", "volume": 1}}]}"#,
+        );
+        controller.scenario_manager.set_max_duration(1);
+        let (mut transport, _client) = ChannelTransport::pair();
+        controller.serve(&mut transport);
+        assert!(controller.is_finished());
+    }
+
+    #[test]
+    fn phases_change_without_runners() {
+        let mut controller = controller(
+            r#"{"phase": [{"name": "warm-up", "duration": 1, "scenario": [{"journey": "t:j",
+                "volume_model": {"type": "constant", "volume": 1}}]}]}"#,
+        );
+        let (mut transport, _client) = ChannelTransport::pair();
+        controller.serve(&mut transport);
+        assert!(controller.is_finished());
+        let phases: Vec<Option<String>> = controller
+            .report()
+            .phase_changes
+            .into_iter()
+            .map(|change| change.phase)
+            .collect();
+        assert_eq!(phases, vec![Some("warm-up".to_string()), None]);
+        assert!(controller.scenario_manager.take_phase_changes().is_empty());
+    }
+
+    #[test]
+    fn reconnecting_runners_keep_their_id() {
+        let mut controller = controller(
+            r#"{"scenario": [{"journey": "t:j", "volume_model":
+                {"type": "constant", "volume": 4}}]}"#,
+        );
+        let identified = |identity: &str| HelloData {
+            identity: Some(identity.to_string()),
+            ..Default::default()
+        };
+        let runner_id = controller.hello(identified("host-1"));
+        controller.work_tracker.set_actual(runner_id, HashMap::from([(0, 3)]));
+
+        assert_eq!(controller.hello(identified("host-1")), runner_id);
+        assert!(controller.work_tracker.get_runner_work(runner_id).is_empty());
+        assert_eq!(controller.work_tracker.total_work.get(&0), Some(&0));
+        assert_eq!(controller.get_runner_count(), 1);
+
+        let other = controller.hello(identified("host-2"));
+        assert_ne!(other, runner_id);
+        assert_eq!(controller.get_runner_count(), 2);
+        // a runner that said bye gets a fresh id when it comes back
+        controller.bye(other);
+        assert_ne!(controller.hello(identified("host-2")), other);
+    }
+
+    #[test]
+    fn serves_work_until_the_scenario_ends() {
+        let mut controller = controller(
+            r#"{"scenario": [{"journey": "t:j", "volume_model":
+                {"type": "constant", "volume": 4}, "duration": 2}]}"#,
+        );
+        let (running, stopped) = serve(&mut controller, |client| {
+            let runner_id = hello(&client, false);
+            let mut running = HashMap::new();
+            let started = Instant::now();
+            loop {
+                // a runner that didn't ask for reductions gets three elements
+                let (work, _, stop): (Work, Config, bool) =
+                    request(&client, &(2, message(runner_id, &running)));
+                for (scenario_id, _, _, _) in work {
+                    *running.entry(scenario_id).or_insert(0) += 1;
                 }
-                MessageType::Bye => {
-                    // [TODO] update runner tracker
-                    println!("Bye received");
-                    // socket.send("Bye received".as_bytes(), 0).unwrap();
+                if stop {
+                    let _: () = request(&client, &(3, message(runner_id, &running)));
+                    return (running, started.elapsed());
                 }
+                thread::sleep(Duration::from_millis(10));
             }
+        });
+        assert_eq!(running, HashMap::from([(0, 4)]));
+        assert!(stopped >= Duration::from_secs(1));
+        assert!(controller.is_finished());
+        assert!(controller.report().timeline.contains_key(&0));
+    }
 
-            std::thread::sleep(std::time::Duration::from_secs(1));
-        }
-        // println!("Running controller with message socket: {}, controller socket: {}, scenario spec: {}, debug: {}", self.message_socket, self.controller_socket, self.scenario_spec, self.debug);
+    #[test]
+    fn runners_that_ask_for_reductions_shed_work() {
+        let mut controller = controller(
+            r#"{"scenario": [{"journey": "t:j", "volume_model":
+                {"type": "constant", "volume": 3}}]}"#,
+        );
+        let reductions = serve(&mut controller, |client| {
+            let runner_id = hello(&client, true);
+            let mut running = HashMap::new();
+            while running.get(&0) != Some(&3) {
+                let (work, _, _, _): (Work, Config, bool, HashMap<i32, i32>) =
+                    request(&client, &(2, message(runner_id, &running)));
+                *running.entry(0).or_insert(0) += work.len() as i32;
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let command = ControlCommand::RemoveScenario { scenario_id: 0 };
+            let _: ControlReply = request(&client, &(4, command));
+            let (work, _, stop, reductions): (Work, Config, bool, HashMap<i32, i32>) =
+                request(&client, &(2, message(runner_id, &running)));
+            assert!(work.is_empty());
+            assert!(stop);
+            let _: () = request(&client, &(3, message(runner_id, &HashMap::new())));
+            reductions
+        });
+        assert_eq!(reductions, HashMap::from([(0, 3)]));
+        assert!(controller.is_finished());
     }
 }
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+use super::runner_tracker::RunnerRecord;
+use super::scenario_manager::{MultiplierChange, PhaseChange};
+use super::timeline::PeriodVolume;
+
+/// Summary of a finished test, printed when the controller exits and
+/// optionally written out as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TestReport {
+    pub scenario_spec: String,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub runners: BTreeMap<i32, RunnerRecord>,
+    pub multiplier_changes: Vec<MultiplierChange>,
+    /// Required against achieved volume per scenario, period by period.
+    /// Periods are tagged with the phase they ran in, if the test has phases.
+    pub timeline: BTreeMap<i32, Vec<PeriodVolume>>,
+    pub phase_changes: Vec<PhaseChange>,
+    /// Why the test was stopped early, if an abort condition was met.
+    pub abort_reason: Option<String>,
+}
+
+impl TestReport {
+    pub fn print(&self) {
+        println!(
+            "Test report for {} ({}s)",
+            self.scenario_spec,
+            // wall clock times, which may have been stepped back during the test
+            self.end_time.saturating_sub(self.start_time)
+        );
+        if let Some(abort_reason) = &self.abort_reason {
+            println!("Aborted: {}", abort_reason);
+        }
+        println!(
+            "runner\tidentity\tfirst_seen\tlast_seen\trequests\tissued\treduced\tcompleted\terrors"
+        );
+        for (runner_id, record) in self.runners.iter() {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                runner_id,
+                record.identity.as_deref().unwrap_or("-"),
+                record.first_seen,
+                record.last_seen,
+                record.request_count,
+                record.work_issued,
+                record.work_reduced,
+                record.work_completed,
+                record.errors
+            );
+        }
+        if !self.multiplier_changes.is_empty() {
+            println!("time\tscenario_time\tscenario\tmultiplier");
+            for change in self.multiplier_changes.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    change.time,
+                    change.scenario_time,
+                    change
+                        .scenario_id
+                        .map_or("all".to_string(), |id| id.to_string()),
+                    change.multiplier
+                );
+            }
+        }
+        if !self.timeline.is_empty() {
+            println!("scenario\tperiods\tmean required\tmean achieved\tachieved/required");
+            for (scenario_id, periods) in self.timeline.iter() {
+                let achieved: Vec<&PeriodVolume> = periods
+                    .iter()
+                    .filter(|period| period.achieved.is_some())
+                    .collect();
+                if achieved.is_empty() {
+                    continue;
+                }
+                let required: f64 =
+                    achieved.iter().map(|period| period.required as f64).sum();
+                let actual: f64 = achieved.iter().filter_map(|period| period.achieved).sum();
+                let count = achieved.len() as f64;
+                println!(
+                    "{}\t{}\t{:.1}\t{:.1}\t{:.3}",
+                    scenario_id,
+                    achieved.len(),
+                    required / count,
+                    actual / count,
+                    if required > 0.0 { actual / required } else { 0.0 }
+                );
+            }
+        }
+        if !self.phase_changes.is_empty() {
+            self.print_phases();
+        }
+    }
+
+    /// Required against achieved volume summed over every scenario, for each
+    /// phase in the order they ran.
+    fn print_phases(&self) {
+        let phases = self
+            .phase_changes
+            .iter()
+            .filter_map(|change| change.phase.as_ref().map(|phase| (phase, change)));
+        println!("phase\tstart\tperiods\tmean required\tmean achieved\tachieved/required");
+        for (phase, change) in phases {
+            let achieved: Vec<&PeriodVolume> = self
+                .timeline
+                .values()
+                .flatten()
+                .filter(|period| period.phase.as_ref() == Some(phase) && period.achieved.is_some())
+                .collect();
+            let mut periods: Vec<u64> = achieved.iter().map(|period| period.start).collect();
+            periods.sort();
+            periods.dedup();
+            let required: f64 = achieved.iter().map(|period| period.required as f64).sum();
+            let actual: f64 = achieved.iter().filter_map(|period| period.achieved).sum();
+            let count = periods.len().max(1) as f64;
+            println!(
+                "{}\t{}\t{}\t{:.1}\t{:.1}\t{:.3}",
+                phase,
+                change.scenario_time,
+                periods.len(),
+                required / count,
+                actual / count,
+                if required > 0.0 { actual / required } else { 0.0 }
+            );
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
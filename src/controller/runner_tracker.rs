@@ -1,9 +1,109 @@
+use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
 
+/// What a runner advertises about itself on Hello.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunnerCapacity {
+    /// Weights the runner's share of the total work. Treated as 1 when unset.
+    #[serde(default)]
+    pub cores: Option<u32>,
+    /// Hard cap on the journeys the runner will run at once.
+    #[serde(default)]
+    pub max_concurrent: Option<i32>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl RunnerCapacity {
+    pub fn weight(&self) -> f64 {
+        self.cores.unwrap_or(1).max(1) as f64
+    }
+}
+
+/// Everything the controller knows about one runner, kept after it says bye
+/// so it can be included in the end-of-test report.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunnerRecord {
+    pub identity: Option<String>,
+    /// Whether the runner asked for reductions in its request_work replies.
+    pub accepts_reductions: bool,
+    pub capacity: RunnerCapacity,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// False once the runner has said bye.
+    pub connected: bool,
+    pub request_count: u64,
+    /// Work the runner last reported, by scenario id.
+    pub current_work: HashMap<i32, i32>,
+    pub work_issued: i64,
+    pub work_reduced: i64,
+    pub work_completed: i64,
+    pub errors: i64,
+}
+
+/// Requests per second over a sliding window, kept as one bucket per second
+/// in a ring so that recording a hit and reading the rate are both O(1)
+/// (amortised over the seconds that pass between calls).
+struct HitWindow {
+    buckets: Vec<u64>,
+    /// Sum of `buckets`.
+    total: u64,
+    /// The second the bucket at `head` counts hits for.
+    head_time: u64,
+    head: usize,
+    first_hit: Option<u64>,
+}
+
+impl HitWindow {
+    fn new(window: u64) -> Self {
+        Self {
+            buckets: vec![0; window.max(1) as usize],
+            total: 0,
+            head_time: 0,
+            head: 0,
+            first_hit: None,
+        }
+    }
+
+    /// Move the head forward to second `t`, emptying the buckets that fall
+    /// out of the window. A clock that goes backwards is treated as standing still.
+    fn advance(&mut self, t: u64) {
+        if t <= self.head_time {
+            return;
+        }
+        let steps = (t - self.head_time).min(self.buckets.len() as u64);
+        for _ in 0..steps {
+            self.head = (self.head + 1) % self.buckets.len();
+            self.total -= self.buckets[self.head];
+            self.buckets[self.head] = 0;
+        }
+        self.head_time = t;
+    }
+
+    fn hit(&mut self, t: u64) {
+        self.advance(t);
+        self.buckets[self.head] += 1;
+        self.total += 1;
+        self.first_hit.get_or_insert(t);
+    }
+
+    fn rate(&mut self, t: u64) -> f64 {
+        self.advance(t);
+        let first_hit = match self.first_hit {
+            Some(first_hit) => first_hit,
+            None => return 0.0,
+        };
+        // don't dilute the rate with the part of the window before the first hit
+        let span = (t.saturating_sub(first_hit) + 1).min(self.buckets.len() as u64);
+        self.total as f64 / span as f64
+    }
+}
+
 pub struct RunnerTracker {
-    hits: Vec<u64>,
-    last_seen: HashMap<i32, u64>,
+    hits: HitWindow,
+    runners: HashMap<i32, RunnerRecord>,
+    identities: HashMap<String, i32>,
     timeout: u64,
     debug: bool,
 }
@@ -11,23 +111,98 @@ pub struct RunnerTracker {
 impl RunnerTracker {
     pub fn new(timeout: u64, debug: bool) -> Self {
         Self {
-            hits: Vec::new(),
-            last_seen: HashMap::new(),
-            timeout: timeout,
+            hits: HitWindow::new(timeout),
+            runners: HashMap::new(),
+            identities: HashMap::new(),
+            timeout,
             debug,
         }
     }
 
-    pub fn update(&mut self, runner_id: i32) {
+    fn record(&mut self, runner_id: i32) -> &mut RunnerRecord {
+        let t = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let debug = self.debug;
+        self.runners.entry(runner_id).or_insert_with(|| {
+            if debug {
+                println!("Tracking runner id: {}", runner_id);
+            }
+            RunnerRecord {
+                first_seen: t,
+                ..Default::default()
+            }
+        })
+    }
+
+    /// Record a request_work call from a runner.
+    pub fn update(&mut self, runner_id: i32, current_work: &HashMap<i32, i32>, errors: i64) {
+        self.touch(runner_id);
+        let record = self.record(runner_id);
+        record.request_count += 1;
+        record.current_work = current_work.clone();
+        record.errors += errors;
+
+        let t = record.last_seen;
+        self.hits.hit(t);
+    }
+
+    /// Record the work handed to and taken back from a runner.
+    pub fn record_work(&mut self, runner_id: i32, issued: i64, reduced: i64, completed: i64) {
+        let record = self.record(runner_id);
+        record.work_issued += issued;
+        record.work_reduced += reduced;
+        record.work_completed += completed;
+    }
+
+    /// Start tracking a runner that has said hello.
+    pub fn register(
+        &mut self,
+        runner_id: i32,
+        identity: Option<String>,
+        accepts_reductions: bool,
+        capacity: RunnerCapacity,
+    ) {
+        self.touch(runner_id);
+        if let Some(identity) = &identity {
+            self.identities.insert(identity.clone(), runner_id);
+        }
+        let record = self.record(runner_id);
+        record.identity = identity;
+        record.accepts_reductions = accepts_reductions;
+        record.current_work.clear();
+        self.set_capacity(runner_id, capacity);
+    }
+
+    /// Mark a runner as alive without counting a hit against the hit rate.
+    pub fn touch(&mut self, runner_id: i32) {
         let t = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        self.last_seen.insert(runner_id, t);
-        self.hits.push(t);
-        if self.hits[0] < t - self.timeout {
-            self.hits.remove(0);
+        let record = self.record(runner_id);
+        record.last_seen = t;
+        record.connected = true;
+    }
+
+    /// Stop counting a runner that has said bye as active. Its record is kept.
+    pub fn remove(&mut self, runner_id: i32) {
+        if let Some(record) = self.runners.get_mut(&runner_id) {
+            record.connected = false;
+            record.current_work.clear();
         }
+        self.identities.retain(|_, id| *id != runner_id);
+    }
+
+    pub fn accepts_reductions(&self, runner_id: i32) -> bool {
+        self.runners
+            .get(&runner_id)
+            .is_some_and(|record| record.accepts_reductions)
+    }
+
+    pub fn find_identity(&self, identity: &str) -> Option<i32> {
+        self.identities.get(identity).copied()
     }
 
     pub fn get_active(&self) -> Vec<i32> {
@@ -36,26 +211,56 @@ impl RunnerTracker {
             .unwrap()
             .as_secs();
         let mut active = Vec::new();
-        for (k, v) in self.last_seen.iter() {
-            if *v + self.timeout > t {
+        for (k, v) in self.runners.iter() {
+            if v.connected && v.last_seen + self.timeout > t {
                 active.push(*k);
             }
         }
         active
     }
 
-    pub fn get_hit_rate(&self) -> f64 {
+    pub fn get_records(&self) -> HashMap<i32, RunnerRecord> {
+        self.runners.clone()
+    }
+
+    pub fn set_capacity(&mut self, runner_id: i32, capacity: RunnerCapacity) {
+        if self.debug {
+            println!("Runner id: {} capacity: {:?}", runner_id, capacity);
+        }
+        self.record(runner_id).capacity = capacity;
+    }
+
+    pub fn get_capacity(&self, runner_id: i32) -> RunnerCapacity {
+        self.runners
+            .get(&runner_id)
+            .map(|record| record.capacity.clone())
+            .unwrap_or_default()
+    }
+
+    /// Weight and labels of every active runner.
+    pub fn get_fleet(&self) -> Vec<(f64, Vec<String>)> {
+        self.get_active()
+            .iter()
+            .map(|id| {
+                let capacity = self.get_capacity(*id);
+                (capacity.weight(), capacity.labels)
+            })
+            .collect()
+    }
+
+    pub fn get_hit_rate(&mut self) -> f64 {
         let t = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        let mut hits = self.hits.clone();
-        while hits[0] < t - self.timeout {
-            hits.remove(0);
-        }
-        hits.len() as f64 / self.timeout as f64
+        self.hits.rate(t)
     }
 
+    /// Measure the hit rate over the last `window` seconds. Hits recorded so
+    /// far are discarded.
+    pub fn set_hit_rate_window(&mut self, window: u64) {
+        self.hits = HitWindow::new(window);
+    }
 
 
     // pub fn heartbeat(&mut self) {
@@ -79,3 +284,59 @@ impl RunnerTracker {
     //     self.hits
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn rate_without_hits_is_zero() {
+        let mut hits = HitWindow::new(10);
+        assert_eq!(hits.rate(NOW), 0.0);
+    }
+
+    #[test]
+    fn first_hit_starts_the_window() {
+        // head_time starts at 0, far behind any real clock
+        let mut hits = HitWindow::new(10);
+        hits.hit(NOW);
+        hits.hit(NOW);
+        assert_eq!(hits.rate(NOW), 2.0);
+        hits.hit(NOW + 1);
+        assert_eq!(hits.rate(NOW + 1), 1.5);
+    }
+
+    #[test]
+    fn hits_older_than_the_window_drop_out() {
+        let mut hits = HitWindow::new(10);
+        hits.hit(NOW);
+        assert_eq!(hits.rate(NOW + 9), 0.1);
+        assert_eq!(hits.rate(NOW + 10), 0.0);
+        hits.hit(NOW + 100);
+        assert_eq!(hits.rate(NOW + 100), 0.1);
+    }
+
+    #[test]
+    fn clock_going_backwards_stands_still() {
+        let mut hits = HitWindow::new(10);
+        hits.hit(NOW);
+        hits.hit(NOW - 5);
+        assert_eq!(hits.total, 2);
+        assert_eq!(hits.rate(NOW - 5), 2.0);
+    }
+
+    #[test]
+    fn hit_rate_window_is_configurable() {
+        let mut tracker = RunnerTracker::new(10, false);
+        tracker.update(1, &HashMap::new(), 0);
+        tracker.set_hit_rate_window(3);
+        assert_eq!(tracker.hits.buckets.len(), 3);
+        assert_eq!(tracker.get_hit_rate(), 0.0);
+        tracker.update(1, &HashMap::new(), 0);
+        assert!(tracker.get_hit_rate() > 0.0);
+        tracker.set_hit_rate_window(0);
+        assert_eq!(tracker.hits.buckets.len(), 1);
+    }
+}
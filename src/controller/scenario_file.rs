@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use super::scenario_manager::{PhaseDefinition, Placement, RampLimits, ScenarioDefinition};
+use super::volume_model::{Datapool, NativeVolumeModel, VolumeModel};
+
+/// A test defined as data rather than a Python generator, e.g. in TOML:
+///
+/// ```toml
+/// [[scenario]]
+/// journey = "t:j"
+/// datapool = "cards"
+/// volume_model = { type = "ramp", from = 0, to = 50, over = 60 }
+/// duration = 600
+/// labels = ["dmz"]
+/// max_step = 20
+///
+/// [[phase]]
+/// name = "peak"
+/// duration = 3600
+///
+/// [[phase.scenario]]
+/// journey = "t:checkout"
+/// volume_model = { type = "constant", volume = 100 }
+/// ```
+///
+/// Top-level scenarios run for the whole test, while each phase's scenarios
+/// run only for that phase.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScenarioFile {
+    #[serde(rename = "scenario", default)]
+    pub scenarios: Vec<ScenarioEntry>,
+    #[serde(rename = "phase", default)]
+    pub phases: Vec<PhaseEntry>,
+}
+
+impl ScenarioFile {
+    pub fn scenario_definitions(&self) -> Vec<ScenarioDefinition> {
+        self.scenarios
+            .iter()
+            .cloned()
+            .map(ScenarioDefinition::from)
+            .collect()
+    }
+
+    pub fn phase_definitions(&self) -> Vec<PhaseDefinition> {
+        self.phases
+            .iter()
+            .cloned()
+            .map(PhaseDefinition::from)
+            .collect()
+    }
+}
+
+/// A named stage of the test, such as warm-up or soak.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhaseEntry {
+    pub name: String,
+    /// Seconds the phase lasts for; without one it lasts until all of its
+    /// scenarios have finished.
+    #[serde(default)]
+    pub duration: Option<u64>,
+    #[serde(rename = "scenario", default)]
+    pub scenarios: Vec<ScenarioEntry>,
+}
+
+impl From<PhaseEntry> for PhaseDefinition {
+    fn from(entry: PhaseEntry) -> Self {
+        Self {
+            name: entry.name,
+            duration: entry.duration,
+            scenarios: entry
+                .scenarios
+                .into_iter()
+                .map(ScenarioDefinition::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScenarioEntry {
+    pub journey: String,
+    #[serde(default)]
+    pub datapool: Option<String>,
+    pub volume_model: NativeVolumeModel,
+    /// Seconds the scenario runs for, however long its volume model would.
+    #[serde(default)]
+    pub duration: Option<u64>,
+    /// Same keys as the options dict a scenario generator may yield.
+    #[serde(flatten)]
+    pub placement: Placement,
+    #[serde(flatten)]
+    pub ramp: RampLimits,
+}
+
+impl From<ScenarioEntry> for ScenarioDefinition {
+    fn from(entry: ScenarioEntry) -> Self {
+        Self {
+            journey_spec: entry.journey,
+            datapool: entry.datapool.map(Datapool::Named),
+            volumemodel: VolumeModel::Native(entry.volume_model),
+            duration: entry.duration,
+            placement: entry.placement,
+            ramp: entry.ramp,
+        }
+    }
+}
+
+/// Whether `scenario_spec` names a scenario file rather than a Python
+/// `module:function`.
+pub fn is_scenario_file(scenario_spec: &str) -> bool {
+    [".toml", ".yaml", ".yml"]
+        .iter()
+        .any(|extension| scenario_spec.ends_with(extension))
+}
+
+/// Read a TOML or YAML scenario file, picking the format by extension.
+pub fn load(path: &str) -> Result<ScenarioFile, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(Path::new(path))?;
+    if path.ends_with(".toml") {
+        Ok(toml::from_str(&contents)?)
+    } else {
+        Ok(serde_yaml::from_str(&contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_belongs_to_the_scenario() {
+        let file: ScenarioFile = toml::from_str(
+            r#"
+            [[scenario]]
+            journey = "t:j"
+            volume_model = { type = "constant", volume = 10 }
+            duration = 300
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.scenario_definitions()[0].duration, Some(300));
+
+        let model_duration = toml::from_str::<ScenarioFile>(
+            r#"
+            [[scenario]]
+            journey = "t:j"
+            volume_model = { type = "constant", volume = 10, duration = 300 }
+            "#,
+        );
+        assert!(model_duration.is_err());
+    }
+}
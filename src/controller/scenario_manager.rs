@@ -1,88 +1,421 @@
+use clap::ValueEnum;
+#[cfg(feature = "python")]
 use pyo3::prelude::*;
-use pyo3::types::PyList;
-use pyo3::types::PyTuple;
-use std::cmp::{max, min};
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
-use std::time::SystemTime;
+#[cfg(feature = "python")]
+use pyo3::types::PyDict;
+use serde::{Deserialize, Serialize};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::scenario_source::ScenarioSource;
+use super::timeline::{PeriodVolume, Timeline};
+use super::token_bucket::{SpawnMetrics, TokenBucket};
+use super::volume_evaluator::{Evaluation, EvaluationRequest, VolumeEvaluator};
+use super::volume_model::{Datapool, VolumeModel};
+
+/// Units of work handed to a runner: (scenario id, data id, journey spec, data).
+pub type Work = Vec<(i32, i32, String, String)>;
+
+/// The largest multiplier `set_multiplier` accepts.
+pub const MAX_MULTIPLIER: f64 = 1000.0;
+/// Most instances one scenario is ever required to run, so that summing the
+/// volumes of many scenarios can't overflow.
+const MAX_VOLUME: f64 = 1_000_000.0;
 
 #[derive(Debug, Clone)]
 pub struct Scenario {
-    journey_spec: Py<PyAny>,
-    datapool: Py<PyAny>,
-    volumemodel: Py<PyAny>,
+    journey_spec: String,
+    datapool: Option<Datapool>,
+    volumemodel: VolumeModel,
+    /// Scenario time the scenario was added at, which native volume models
+    /// count from.
+    started_at: u64,
+    /// Seconds after `started_at` the scenario is retired, whatever its
+    /// volume model says.
+    duration: Option<u64>,
+    placement: Placement,
+    ramp: RampLimits,
+    spawn_limiter: Option<TokenBucket>,
+    /// Units handed out since the current period began, for `ramp.max_step`.
+    spawned_this_period: i32,
+}
+
+/// A scenario as yielded by a scenario generator or sent to the controller
+/// socket, before it is given an id.
+pub struct ScenarioDefinition {
+    pub journey_spec: String,
+    pub datapool: Option<Datapool>,
+    pub volumemodel: VolumeModel,
+    /// Seconds the scenario runs for before it is retired, if limited.
+    pub duration: Option<u64>,
+    pub placement: Placement,
+    pub ramp: RampLimits,
+}
+
+/// A named stage of a test, such as warm-up or soak. Phases run one after
+/// another, each with its own scenarios, which are retired when it ends.
+pub struct PhaseDefinition {
+    pub name: String,
+    /// Seconds the phase lasts for; without one it lasts until all of its
+    /// scenarios have finished.
+    pub duration: Option<u64>,
+    pub scenarios: Vec<ScenarioDefinition>,
+}
+
+/// The phase running now.
+struct Phase {
+    name: String,
+    /// Scenario time the phase began at.
+    started_at: u64,
+    duration: Option<u64>,
+    scenario_ids: Vec<i32>,
+}
+
+/// A transition from one phase to the next, announced on the message socket
+/// and kept for the end-of-test report.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PhaseChange {
+    /// Wall-clock second the change was made.
+    pub time: u64,
+    pub scenario_time: u64,
+    /// The phase now running, unset once the last one has ended.
+    pub phase: Option<String>,
+    pub previous: Option<String>,
+}
+
+/// The scenario ids affected by a reload.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ReloadReport {
+    pub added: Vec<i32>,
+    pub removed: Vec<i32>,
+    /// Matched scenarios whose volume model, datapool or limits changed.
+    pub updated: Vec<i32>,
+    pub unchanged: Vec<i32>,
+}
+
+/// Which runners a scenario's work may be handed to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Placement {
+    /// The runner must carry every one of these labels.
+    #[serde(rename = "labels")]
+    pub required_labels: Vec<String>,
+    /// The runner must carry none of these labels.
+    pub anti_affinity: Vec<String>,
+    /// Most units of the scenario a single runner may hold at once.
+    pub max_per_runner: Option<i32>,
+}
+
+impl Placement {
+    fn allows(&self, labels: &[String]) -> bool {
+        self.required_labels.iter().all(|label| labels.contains(label))
+            && !self.anti_affinity.iter().any(|label| labels.contains(label))
+    }
+
+    /// Read the optional fourth element of a scenario tuple, a dict such as
+    /// `{"labels": ["dmz"], "anti_affinity": ["shared"], "max_per_runner": 10}`.
+    #[cfg(feature = "python")]
+    pub(crate) fn from_python(options: &PyAny) -> PyResult<Self> {
+        let options: &PyDict = options.downcast()?;
+        let mut placement = Self::default();
+        if let Some(labels) = options.get_item("labels")? {
+            placement.required_labels = labels.extract()?;
+        }
+        if let Some(anti_affinity) = options.get_item("anti_affinity")? {
+            placement.anti_affinity = anti_affinity.extract()?;
+        }
+        if let Some(max_per_runner) = options.get_item("max_per_runner")? {
+            placement.max_per_runner = max_per_runner.extract()?;
+        }
+        Ok(placement)
+    }
+}
+
+/// How quickly a scenario may ramp up, on top of the global spawn rate.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RampLimits {
+    /// New journey instances per second for this scenario alone.
+    pub spawn_rate: Option<f64>,
+    /// Instances that may start at once, defaults to one second's worth.
+    pub spawn_burst: Option<f64>,
+    /// Most new instances handed out in a single period.
+    pub max_step: Option<i32>,
+}
+
+impl RampLimits {
+    /// Read ramp limits from the same optional dict as `Placement`, e.g.
+    /// `{"spawn_rate": 5, "spawn_burst": 10, "max_step": 20}`.
+    #[cfg(feature = "python")]
+    pub(crate) fn from_python(options: &PyAny) -> PyResult<Self> {
+        let options: &PyDict = options.downcast()?;
+        let mut ramp = Self::default();
+        if let Some(spawn_rate) = options.get_item("spawn_rate")? {
+            ramp.spawn_rate = spawn_rate.extract()?;
+        }
+        if let Some(spawn_burst) = options.get_item("spawn_burst")? {
+            ramp.spawn_burst = spawn_burst.extract()?;
+        }
+        if let Some(max_step) = options.get_item("max_step")? {
+            ramp.max_step = max_step.extract()?;
+        }
+        Ok(ramp)
+    }
+
+    fn spawn_limiter(&self) -> Option<TokenBucket> {
+        self.spawn_rate.map(|spawn_rate| {
+            TokenBucket::new(spawn_rate, self.spawn_burst.unwrap_or(spawn_rate))
+        })
+    }
+}
+
+/// What get_work needs to know about the runner asking for work.
+pub struct RunnerRequest {
+    /// The runner's current work, by scenario id.
+    pub work: HashMap<i32, i32>,
+    /// The runner's capacity weight.
+    pub weight: f64,
+    /// Weight and labels of every active runner, this one included, so each
+    /// scenario can be shared among just the runners its placement allows.
+    pub fleet: Vec<(f64, Vec<String>)>,
+    /// The most work the runner will accept in this request.
+    pub self_limit: Option<i32>,
+    pub labels: Vec<String>,
+}
+
+/// A runtime change to a volume multiplier, kept for the end-of-test report.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultiplierChange {
+    /// Wall-clock second the change was made.
+    pub time: u64,
+    pub scenario_time: u64,
+    /// The scenario the multiplier applies to, or every scenario if unset.
+    pub scenario_id: Option<i32>,
+    pub multiplier: f64,
+}
+
+/// How work is shared out between scenarios when a runner's limit is hit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Allocation {
+    /// Round-robin over the scenarios, in a random order each round.
+    Shuffle,
+    /// Weighted by how far each scenario is below its required volume.
+    Deficit,
+}
+
+/// The period of length `period` that scenario time `time` falls in.
+///
+/// Periods tile scenario time from 0, `[0, period)`, `[period, 2 * period)`
+/// and so on, so every window a volume model is asked about starts on a
+/// multiple of the period and no two windows overlap, however irregularly
+/// runners happen to ask for work.
+pub fn period_containing(time: u64, period: u64) -> (u64, u64) {
+    let start = time - time % period;
+    (start, start + period)
 }
 
-#[derive(Clone)]
 pub struct ScenarioManager {
     in_start: bool,
     period: u64,
-    delay: u64,
-    start_time: u64,
-    spawn_rate: u64,
+    /// How long after `origin` scenario time starts.
+    delay: Duration,
+    /// When the manager was created. Scenario time is measured from here on
+    /// the monotonic clock, so wall clock jumps (NTP, DST, an operator
+    /// changing the time) never move it.
+    origin: Instant,
+    /// Paused time left out of scenario time.
+    excluded: Duration,
+    spawn_limiter: TokenBucket,
+    current_period_start: u64,
     current_period_end: u64,
     debug: bool,
     required: HashMap<i32, i32>,
+    /// What the volume models asked for, before multipliers.
+    base_required: HashMap<i32, i32>,
+    global_multiplier: f64,
+    scenario_multipliers: HashMap<i32, f64>,
+    multiplier_changes: Vec<MultiplierChange>,
     scenarios: HashMap<i32, Scenario>,
+    /// Scenarios from the test's own scenario spec, the only ones `reload`
+    /// touches.
+    spec_scenario_ids: HashSet<i32>,
     scenario_id_gen: i32,
+    allocation: Allocation,
+    rng: StdRng,
+    /// When the test was paused, while it is paused.
+    paused_at: Option<Instant>,
+    evaluator: VolumeEvaluator,
+    /// Bumped whenever the scenarios change, invalidating evaluations in flight.
+    generation: u64,
+    /// How many periods past the current one are evaluated ahead of time.
+    lookahead: usize,
+    /// Evaluations of the periods after the current one, in order.
+    upcoming: VecDeque<Evaluation>,
+    /// Lookahead evaluations requested but not yet back.
+    in_flight: usize,
+    /// End of the furthest period requested so far.
+    requested_until: Option<u64>,
+    /// Required and achieved volume of every period so far.
+    timeline: Timeline,
+    /// Scenario time every scenario is retired at, whatever the volume
+    /// models say.
+    max_duration: Option<u64>,
+    /// Phases still to run, in order.
+    phases: VecDeque<PhaseDefinition>,
+    phase: Option<Phase>,
+    phase_changes: Vec<PhaseChange>,
+    /// How many of `phase_changes` have been handed to `take_phase_changes`.
+    phase_changes_taken: usize,
 }
 
 impl ScenarioManager {
     pub fn new(period: u64, delay: u64, spawn_rate: u64, debug: bool) -> Self {
-        let start_time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
         Self {
             in_start: delay > 0,
-            period,
-            delay,
-            start_time,
-            spawn_rate,
+            period: period.max(1),
+            delay: Duration::from_secs(delay),
+            origin: Instant::now(),
+            excluded: Duration::ZERO,
+            spawn_limiter: TokenBucket::new(spawn_rate as f64, spawn_rate as f64),
+            current_period_start: 0,
             current_period_end: 0,
             debug,
             required: HashMap::new(),
+            base_required: HashMap::new(),
+            global_multiplier: 1.0,
+            scenario_multipliers: HashMap::new(),
+            multiplier_changes: Vec::new(),
             scenarios: HashMap::new(),
+            spec_scenario_ids: HashSet::new(),
             scenario_id_gen: 0,
+            allocation: Allocation::Shuffle,
+            rng: StdRng::from_entropy(),
+            paused_at: None,
+            evaluator: VolumeEvaluator::spawn(),
+            generation: 0,
+            lookahead: 1,
+            upcoming: VecDeque::new(),
+            in_flight: 0,
+            requested_until: None,
+            timeline: Timeline::default(),
+            max_duration: None,
+            phases: VecDeque::new(),
+            phase: None,
+            phase_changes: Vec::new(),
+            phase_changes_taken: 0,
         }
     }
 
+    /// Allow up to `burst` journeys to start at once after a quiet spell,
+    /// on top of the steady spawn rate.
+    pub fn set_spawn_burst(&mut self, burst: u64) {
+        self.spawn_limiter.set_burst(burst as f64);
+    }
+
+    /// Start scenario time at wall-clock time `start_at` rather than after
+    /// the start delay. Runners may connect and warm up in the meantime, but
+    /// get no work until then. The wait is measured on the monotonic clock
+    /// from now, so a wall clock change while waiting doesn't move it.
+    pub fn set_start_at(&mut self, start_at: SystemTime) {
+        let wait = match start_at.duration_since(SystemTime::now()) {
+            Ok(wait) => wait,
+            Err(_) => {
+                eprintln!("Scheduled start time has already passed, starting now");
+                Duration::ZERO
+            }
+        };
+        self.delay = self.elapsed() + wait;
+        self.in_start = !wait.is_zero();
+        println!(
+            "Waiting {}s for the scheduled start",
+            self.starts_in().unwrap_or(0)
+        );
+    }
+
+    /// End the test `seconds` into scenario time by retiring every scenario,
+    /// including any added later, whatever their volume models say.
+    pub fn set_max_duration(&mut self, seconds: u64) {
+        self.max_duration = Some(seconds);
+    }
+
+    /// Evaluate volume models this many periods ahead, at least one.
+    pub fn set_lookahead(&mut self, periods: usize) {
+        self.lookahead = periods.max(1);
+    }
+
+    pub fn get_spawn_metrics(&self) -> SpawnMetrics {
+        self.spawn_limiter.metrics()
+    }
+
+    /// Choose the allocation strategy, optionally with a fixed seed so a
+    /// test's allocation can be reproduced.
+    pub fn set_allocation(&mut self, allocation: Allocation, seed: Option<u64>) {
+        self.allocation = allocation;
+        if let Some(seed) = seed {
+            self.rng = StdRng::seed_from_u64(seed);
+        }
+    }
+
+    /// Scenario time: whole seconds since the start delay ended, less any
+    /// paused time left out on resume. Zero until the delay is over.
     pub fn _now(&self) -> u64 {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
+        self.elapsed()
+            .saturating_sub(self.delay)
             .as_secs()
-            - self.start_time
+    }
+
+    /// Whether scenario time has yet to start, during the start delay or
+    /// before a scheduled start.
+    pub fn is_waiting(&self) -> bool {
+        self.elapsed() < self.delay
+    }
+
+    /// Whole seconds, rounded up, until scenario time starts, while waiting.
+    pub fn starts_in(&self) -> Option<u64> {
+        let remaining = self.delay.checked_sub(self.elapsed())?;
+        if remaining.is_zero() {
+            return None;
+        }
+        Some(remaining.as_secs() + (remaining.subsec_nanos() > 0) as u64)
+    }
+
+    /// Time since the manager was created, less any paused time left out.
+    fn elapsed(&self) -> Duration {
+        self.origin.elapsed().saturating_sub(self.excluded)
     }
 
     pub fn get_work(
         &mut self,
         current_work: HashMap<i32, i32>,
-        num_runner_current_work: i32,
-        num_runners: i32,
-        runner_self_limit: Option<i32>,
-        hit_rate: f64,
-    ) -> (Vec<(i32, i32, String, String)>, HashMap<i32, i32>) {
+        runner: &RunnerRequest,
+    ) -> (Work, HashMap<i32, i32>) {
         // println!("Inside get_work");
         let required = self.get_required_work();
+        self.timeline.sample(self.current_period_end, &current_work);
+        if self.is_paused() {
+            return (vec![], HashMap::new());
+        }
         let diff = Self::remove_a_from_b(current_work.clone(), required.clone());
-        let total = required.len() as i32;
-        let mut runners_share_limit =
-            (total as f64) / (num_runners as f64) - (num_runner_current_work as f64);
-
-        // python controller
-        // total=60 num_runners=1 num_runner_current_work=0 runners_share_limit=60.0
-        // rust controller
-        // total=0 num_runners=1 num_runner_current_work=0 runners_share_limit=0.0
-
-        println!(
-            "total={:?} num_runners={:?} num_runner_current_work={:?} runners_share_limit={:?}",
-            total, num_runners, num_runner_current_work, runners_share_limit
-        );
+        let diff = self.placeable(diff, runner);
+        let diff = self.ramp_limited(diff);
+        let total: i32 = required.values().sum();
+        let runner_share: f64 = required
+            .iter()
+            .map(|(scenario_id, volume)| *volume as f64 * self.share(*scenario_id, runner))
+            .sum();
+        let num_runner_current_work: i32 = runner.work.values().sum();
+        let runners_share_limit = runner_share - (num_runner_current_work as f64);
 
-        runners_share_limit = 6.0;
+        if self.debug {
+            println!(
+                "total={:?} runner_share={:?} num_runner_current_work={:?} runners_share_limit={:?}",
+                total, runner_share, num_runner_current_work, runners_share_limit
+            );
+        }
 
         // let mut limit = max(0, runners_share_limit);
         let mut limit = runners_share_limit.max(0.0);
@@ -90,68 +423,43 @@ impl ScenarioManager {
         // println!("1. limit: {}", limit);
 
         // if runner_self_limit is set
-        if let Some(runner_self_limit) = runner_self_limit {
+        if let Some(runner_self_limit) = runner.self_limit {
             // limit = min(limit, runners_share_limit);
             limit = limit.min(runner_self_limit as f64);
         }
 
         // println!("2. limit: {}", limit);
 
-        let spawn_limit = self.spawn_rate as f64 / hit_rate;
-        // limit = min(limit, spawn_limit);
-        limit = limit.min(spawn_limit);
-
-        // println!("spawn_rate: {} hit_rate: {}", self.spawn_rate, hit_rate);
-
-        // println!("3. limit: {}", limit);
-
         if limit % 1.0 != 0.0 && (limit % 1.0 > 0.4) {
-            limit = limit + 1.0;
+            limit += 1.0;
         }
 
-        // println!("4. limit: {}", limit);
+        // println!("3. limit: {}", limit);
 
-        // [TODO] shuffle the keys of diff
-        // let mut diff_keys = diff.keys().collect::<Vec<_>>();
-        // diff_keys.shuffle(&mut thread_rng());
+        // the spawn rate is enforced across the whole fleet, not per runner
+        let wanted = (limit.max(0.0) as usize).min(diff.values().sum::<i32>().max(0) as usize);
+        let granted = wanted.min(self.spawn_limiter.available());
 
-        let mut work: Vec<(i32, i32, String, String)> = vec![];
+        let mut work: Work = vec![];
         let mut scenario_volume_map: HashMap<i32, i32> = HashMap::new();
 
-        for scenario_id in diff.keys() {
-            // if size of work is greater than limit, break
-            if work.len() as f64 >= limit {
-                // println!("breaking because work.len() as f64 >= limit");
-                // println!("work.len(): {}", work.len());
-                // println!("limit: {}", limit);
-                break;
-            }
-            // println!("------------");
-            // println!("scenario_id: {}", scenario_id);
-            // println!("self.scenarios: {:?}", self.scenarios);
-            // println!("------------");
-            if self.scenarios.contains_key(scenario_id) {
-                let scenario = self.scenarios.get(scenario_id).unwrap();
-                work.push((
-                    scenario_id.clone(),
-                    1 as i32,
-                    scenario.journey_spec.to_string(),
-                    "".to_string(),
-                ));
-                // work.append((scenario_id, None, scenario.journey_spec, None))
-            }
-
-            if scenario_volume_map.contains_key(scenario_id) {
-                let volume = scenario_volume_map.get(scenario_id).unwrap();
-                scenario_volume_map.insert(scenario_id.clone(), volume + 1);
-            } else {
-                scenario_volume_map.insert(scenario_id.clone(), 1);
+        for scenario_id in self.allocate(&diff, granted) {
+            let scenario = self.scenarios.get(&scenario_id).unwrap();
+            work.push((
+                scenario_id,
+                1,
+                scenario.journey_spec.clone(),
+                "".to_string(),
+            ));
+            *scenario_volume_map.entry(scenario_id).or_insert(0) += 1;
+        }
+        self.spawn_limiter.take(work.len(), wanted);
+        for (scenario_id, volume) in scenario_volume_map.iter() {
+            let scenario = self.scenarios.get_mut(scenario_id).unwrap();
+            if let Some(spawn_limiter) = scenario.spawn_limiter.as_mut() {
+                spawn_limiter.take(*volume as usize, *volume as usize);
             }
-
-            // let volume = required.get(scenario_id).unwrap();
-            // let work_volume = min(*volume, limit as i32);
-            // limit -= work_volume as f64;
-            // scenario_volume_map.insert(scenario_id, work_volume);
+            scenario.spawned_this_period += volume;
         }
 
         // println!("required: {:?}", required);
@@ -168,6 +476,112 @@ impl ScenarioManager {
         (work, scenario_volume_map)
     }
 
+    /// The runner's fraction of a scenario's volume: its weight over the
+    /// combined weight of the active runners the scenario may be placed on.
+    fn share(&self, scenario_id: i32, runner: &RunnerRequest) -> f64 {
+        let placement = match self.scenarios.get(&scenario_id) {
+            Some(scenario) => &scenario.placement,
+            None => return 0.0,
+        };
+        if !placement.allows(&runner.labels) {
+            return 0.0;
+        }
+        let eligible: f64 = runner
+            .fleet
+            .iter()
+            .filter(|(_, labels)| placement.allows(labels))
+            .map(|(weight, _)| weight)
+            .sum();
+        // a runner not yet counted as active still gets a fair share
+        runner.weight / eligible.max(runner.weight)
+    }
+
+    /// Restrict the deficits in `diff` to the scenarios `runner` may run,
+    /// capped by each scenario's per-runner maximum.
+    fn placeable(&self, mut diff: HashMap<i32, i32>, runner: &RunnerRequest) -> HashMap<i32, i32> {
+        diff.retain(|scenario_id, volume| {
+            let placement = match self.scenarios.get(scenario_id) {
+                Some(scenario) => &scenario.placement,
+                None => return false,
+            };
+            if !placement.allows(&runner.labels) {
+                return false;
+            }
+            if let Some(max_per_runner) = placement.max_per_runner {
+                let held = *runner.work.get(scenario_id).unwrap_or(&0);
+                *volume = (*volume).min(max_per_runner - held);
+            }
+            *volume > 0
+        });
+        diff
+    }
+
+    /// Cap the deficits in `diff` by each scenario's own spawn rate and
+    /// per-period step.
+    fn ramp_limited(&mut self, mut diff: HashMap<i32, i32>) -> HashMap<i32, i32> {
+        diff.retain(|scenario_id, volume| {
+            let scenario = match self.scenarios.get_mut(scenario_id) {
+                Some(scenario) => scenario,
+                None => return false,
+            };
+            if let Some(spawn_limiter) = scenario.spawn_limiter.as_mut() {
+                *volume = (*volume).min(spawn_limiter.available() as i32);
+            }
+            if let Some(max_step) = scenario.ramp.max_step {
+                *volume = (*volume).min(max_step - scenario.spawned_this_period);
+            }
+            *volume > 0
+        });
+        diff
+    }
+
+    /// Pick up to `limit` units of work from the per-scenario deficits in
+    /// `diff`, one scenario id per unit, so that no scenario is starved when
+    /// the limit is hit.
+    fn allocate(&mut self, diff: &HashMap<i32, i32>, limit: usize) -> Vec<i32> {
+        // sorted first so a seeded rng gives the same allocation every run
+        let mut remaining: Vec<(i32, i32)> = diff
+            .iter()
+            .filter(|(scenario_id, volume)| {
+                **volume > 0 && self.scenarios.contains_key(scenario_id)
+            })
+            .map(|(scenario_id, volume)| (*scenario_id, *volume))
+            .collect();
+        remaining.sort();
+
+        let mut allocated = Vec::new();
+        match self.allocation {
+            Allocation::Shuffle => {
+                // one unit per scenario per round, in a fresh random order each round
+                while allocated.len() < limit && !remaining.is_empty() {
+                    remaining.shuffle(&mut self.rng);
+                    for (scenario_id, volume) in remaining.iter_mut() {
+                        if allocated.len() >= limit {
+                            break;
+                        }
+                        allocated.push(*scenario_id);
+                        *volume -= 1;
+                    }
+                    remaining.retain(|(_, volume)| *volume > 0);
+                }
+            }
+            Allocation::Deficit => {
+                // each unit goes to a scenario with probability proportional to its deficit
+                while allocated.len() < limit && !remaining.is_empty() {
+                    let weights =
+                        WeightedIndex::new(remaining.iter().map(|(_, volume)| *volume)).unwrap();
+                    let index = weights.sample(&mut self.rng);
+                    allocated.push(remaining[index].0);
+                    remaining[index].1 -= 1;
+                    if remaining[index].1 == 0 {
+                        remaining.remove(index);
+                    }
+                }
+            }
+        }
+        allocated
+    }
+
     pub fn remove_a_from_b(a: HashMap<i32, i32>, b: HashMap<i32, i32>) -> HashMap<i32, i32> {
         let mut c = b.clone();
         for (k, v) in a.iter() {
@@ -185,167 +599,1014 @@ impl ScenarioManager {
     }
 
     pub fn now(&mut self) -> u64 {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            - self.start_time
+        self._now()
     }
 
+    /// The volumes required right now. Volume models are evaluated ahead on
+    /// the evaluator thread, so this only ever reads cached values; if the
+    /// next period's evaluation isn't ready when the current one ends, the
+    /// current volumes stand until it is. A model slower than the period then
+    /// holds each period's volumes for as long as it takes.
     pub fn get_required_work(&mut self) -> HashMap<i32, i32> {
-        let now = self.now();
+        self.advance();
+        self.required.clone()
+    }
+
+    /// Bring the test up to the current scenario time: take in finished
+    /// evaluations, retire expired scenarios, change phase and move on to
+    /// the period that has begun. Done on every request and between them,
+    /// so deadlines and phase changes don't wait for a runner to ask for work.
+    pub fn advance(&mut self) {
+        self.collect_evaluations();
         if self.in_start {
-            if now <= self.delay {
-                return self.required.clone();
+            if self.is_waiting() {
+                // the first period is evaluated during the delay, ready for it to end
+                self.request_lookahead(0);
+                return;
             }
             self.in_start = false;
-            self.start_time = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+            println!("Start delay over, scenario time starts");
+        }
+        if self.is_paused() {
+            // resuming may wind scenario time back, so nothing it couldn't
+            // undo (retiring scenarios, changing phase) happens while paused
+            return;
         }
-        if now >= self.current_period_end {
+        let now = self.now();
+        self.retire_expired(now);
+        self.advance_phases(now);
+        // a request after a quiet spell may find several periods have begun
+        while now >= self.current_period_end {
+            let evaluation = match self.upcoming.pop_front() {
+                Some(evaluation) => evaluation,
+                None => break,
+            };
             // println!("calling - Updating required and period");
             println!(
                 "current_period_end: {} now {} period {}",
                 self.current_period_end, now, self.period
             );
-            self.update_required_and_period(self.current_period_end, (now + self.period) as u64);
+            self.update_required_and_period(evaluation);
         }
-        self.required.clone()
+        self.request_lookahead(now);
     }
 
-    pub fn add_scenario(
-        &mut self,
-        journey_spec: Py<PyAny>,
-        datapool: Py<PyAny>,
-        volumemodel: Py<PyAny>,
-    ) {
+    /// Retire the scenarios that have run for their duration by scenario
+    /// time `now`, or all of them once the test's max duration is up.
+    fn retire_expired(&mut self, now: u64) {
+        if let Some(max_duration) = self.max_duration.filter(|max| now >= *max) {
+            if self.is_active() {
+                println!("Max duration of {}s reached", max_duration);
+                self.stop();
+            }
+            return;
+        }
+        let mut expired: Vec<i32> = self
+            .scenarios
+            .iter()
+            .filter(|(_, scenario)| {
+                scenario
+                    .duration
+                    .is_some_and(|duration| now >= scenario.started_at + duration)
+            })
+            .map(|(scenario_id, _)| *scenario_id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        expired.sort();
+        for scenario_id in expired {
+            println!("Scenario {} has run for its duration", scenario_id);
+            self.remove_scenario(scenario_id);
+        }
+        if self.scenarios.is_empty() {
+            println!("All scenarios have been removed from scenario tracker");
+        }
+        self.refresh_required();
+    }
+
+    /// End the test: retire every scenario, end the running phase and skip
+    /// any still to come, so runners shed their work and say bye.
+    pub fn stop(&mut self) {
+        let now = if self.in_start { 0 } else { self._now() };
+        self.in_start = false;
+        self.phases.clear();
+        if self.phase.is_some() {
+            self.start_next_phase(now);
+        }
+        let mut scenario_ids: Vec<i32> = self.scenarios.keys().copied().collect();
+        scenario_ids.sort();
+        println!("Stopping, retiring scenarios {:?}", scenario_ids);
+        for scenario_id in scenario_ids {
+            self.remove_scenario(scenario_id);
+        }
+        println!("All scenarios have been removed from scenario tracker");
+        self.refresh_required();
+    }
+
+    /// Move on to the next phase once the current one is over by scenario
+    /// time `now`, as many times as needed after a quiet spell. A phase with
+    /// a duration ends on time; one without ends once its scenarios have all
+    /// finished.
+    fn advance_phases(&mut self, now: u64) {
+        loop {
+            let ends_at = match &self.phase {
+                Some(phase) => match phase.duration {
+                    Some(duration) => phase.started_at + duration,
+                    None if phase
+                        .scenario_ids
+                        .iter()
+                        .any(|scenario_id| self.scenarios.contains_key(scenario_id)) =>
+                    {
+                        return
+                    }
+                    None => now,
+                },
+                None => return,
+            };
+            if now < ends_at {
+                return;
+            }
+            self.start_next_phase(ends_at);
+        }
+    }
+
+    /// Retire the current phase's scenarios and start the next phase's at
+    /// scenario time `at`, announcing the change.
+    fn start_next_phase(&mut self, at: u64) {
+        let previous = self.phase.take();
+        if let Some(previous) = previous.as_ref() {
+            for scenario_id in previous.scenario_ids.iter() {
+                self.remove_scenario(*scenario_id);
+            }
+        }
+        let next = self.phases.pop_front();
+        let change = PhaseChange {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            scenario_time: at,
+            phase: next.as_ref().map(|phase| phase.name.clone()),
+            previous: previous.map(|phase| phase.name),
+        };
+        match (&change.previous, &change.phase) {
+            (_, Some(phase)) => println!("Phase {} started at scenario time {}", phase, at),
+            (Some(previous), None) => {
+                println!("Phase {} ended at scenario time {}", previous, at)
+            }
+            (None, None) => {}
+        }
+        self.phase_changes.push(change);
+        if let Some(next) = next {
+            let scenario_ids = next
+                .scenarios
+                .into_iter()
+                .map(|definition| self.insert_scenario(definition, at))
+                .collect();
+            self.phase = Some(Phase {
+                name: next.name,
+                started_at: at,
+                duration: next.duration,
+                scenario_ids,
+            });
+        }
+        self.refresh_required();
+    }
+
+    /// Keep the next `lookahead` periods evaluated or in flight. Periods that
+    /// are already over by the time they would be requested, after an idle
+    /// spell or behind a model slower than the period, are skipped in favour
+    /// of the period containing `now`.
+    fn request_lookahead(&mut self, now: u64) {
+        while self.upcoming.len() + self.in_flight < self.lookahead {
+            let next = self.requested_until.unwrap_or(self.current_period_end);
+            let (start, end) = if next + self.period <= now {
+                period_containing(now, self.period)
+            } else {
+                (next, next + self.period)
+            };
+            self.request_evaluation(start, end);
+            self.requested_until = Some(end);
+            self.in_flight += 1;
+        }
+    }
+
+    fn request_evaluation(&self, start: u64, end: u64) {
+        let volume_models = self
+            .scenarios
+            .iter()
+            .map(|(scenario_id, scenario)| {
+                (
+                    *scenario_id,
+                    scenario.volumemodel.clone(),
+                    scenario.started_at,
+                )
+            })
+            .collect();
+        self.evaluator.request(EvaluationRequest {
+            generation: self.generation,
+            start,
+            end,
+            volume_models,
+        });
+    }
+
+    /// Take in whatever the evaluator thread has finished. A refresh of the
+    /// current period applies straight away and lookahead evaluations are
+    /// kept until their period begins. Evaluations for scenarios that have
+    /// since changed are dropped.
+    fn collect_evaluations(&mut self) {
+        for evaluation in self.evaluator.results() {
+            if self.debug {
+                println!(
+                    "Evaluated volume models for {}-{} in {:?}",
+                    evaluation.start, evaluation.end, evaluation.elapsed
+                );
+            }
+            if evaluation.generation != self.generation {
+                continue;
+            }
+            if evaluation.start >= self.current_period_end {
+                self.in_flight = self.in_flight.saturating_sub(1);
+                self.upcoming.push_back(evaluation);
+            } else if evaluation.end == self.current_period_end {
+                self.update_required_and_period(evaluation);
+            }
+        }
+    }
+
+    /// Forget evaluations made for a set of scenarios that has since changed.
+    fn invalidate_evaluations(&mut self) {
+        self.generation += 1;
+        self.upcoming.clear();
+        self.in_flight = 0;
+        self.requested_until = None;
+    }
+
+    pub fn add_scenario(&mut self, definition: ScenarioDefinition) -> i32 {
+        // scenario time only starts counting once the start delay is over
+        let started_at = if self.in_start { 0 } else { self._now() };
+        self.insert_scenario(definition, started_at)
+    }
+
+    fn insert_scenario(&mut self, definition: ScenarioDefinition, started_at: u64) -> i32 {
         let scenario_id = self.scenario_id_gen;
         self.scenario_id_gen += 1;
-        let journey_spec_debug = journey_spec.clone();
-        let datapool_debug = datapool.clone();
-        let volumemodel_debug = volumemodel.clone();
+        self.invalidate_evaluations();
+        println!(
+            "Added scenario id={} journey_spec={} datapool={} volumemodel={} duration={:?} \
+             placement={:?} ramp={:?}",
+            scenario_id,
+            definition.journey_spec,
+            definition
+                .datapool
+                .as_ref()
+                .map_or("none".to_string(), |datapool| datapool.to_string()),
+            definition.volumemodel,
+            definition.duration,
+            definition.placement,
+            definition.ramp
+        );
         self.scenarios.insert(
             scenario_id,
             Scenario {
-                journey_spec,
-                datapool,
-                volumemodel,
+                journey_spec: definition.journey_spec,
+                datapool: definition.datapool,
+                volumemodel: definition.volumemodel,
+                started_at,
+                duration: definition.duration,
+                placement: definition.placement,
+                spawn_limiter: definition.ramp.spawn_limiter(),
+                ramp: definition.ramp,
+                spawned_this_period: 0,
             },
         );
+        scenario_id
+    }
+
+    /// Stop running a scenario. Its work is shed by the runners on their
+    /// next request, since it is no longer required.
+    pub fn remove_scenario(&mut self, scenario_id: i32) -> bool {
+        self.required.remove(&scenario_id);
+        self.base_required.remove(&scenario_id);
+        let removed = self.scenarios.remove(&scenario_id).is_some();
+        if removed {
+            self.invalidate_evaluations();
+            println!("Removed scenario {}", scenario_id);
+        }
+        removed
+    }
+
+    /// Re-read the scenario source and bring the running scenarios in line
+    /// with what it yields now, without restarting the test.
+    ///
+    /// Old and new scenarios are matched by journey spec, in order, so a
+    /// journey that appears twice matches its first and second occurrences.
+    /// Matched scenarios keep their id, work and the scenario time base and
+    /// take on the new volume model, datapool, placement and ramp limits.
+    /// Unmatched old scenarios are retired and unmatched new ones added.
+    /// Only scenarios from the spec are matched, so phases and scenarios
+    /// added at runtime are left alone.
+    pub fn reload(
+        &mut self,
+        source: &dyn ScenarioSource,
+    ) -> Result<ReloadReport, Box<dyn std::error::Error>> {
+        let definitions = source.load()?;
+
+        let mut existing: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut scenario_ids: Vec<&i32> = self
+            .scenarios
+            .keys()
+            .filter(|scenario_id| self.spec_scenario_ids.contains(scenario_id))
+            .collect();
+        scenario_ids.sort();
+        for scenario_id in scenario_ids {
+            let journey_spec = self.scenarios[scenario_id].journey_spec.to_string();
+            existing.entry(journey_spec).or_default().push(*scenario_id);
+        }
+        for scenario_ids in existing.values_mut() {
+            scenario_ids.reverse();
+        }
+
+        let mut report = ReloadReport::default();
+        for definition in definitions {
+            let matched = existing
+                .get_mut(&definition.journey_spec.to_string())
+                .and_then(|scenario_ids| scenario_ids.pop());
+            let scenario_id = match matched {
+                Some(scenario_id) => scenario_id,
+                None => {
+                    let scenario_id = self.add_scenario(definition);
+                    self.spec_scenario_ids.insert(scenario_id);
+                    report.added.push(scenario_id);
+                    continue;
+                }
+            };
+            let scenario = self.scenarios.get_mut(&scenario_id).unwrap();
+            if Self::unchanged(scenario, &definition) {
+                report.unchanged.push(scenario_id);
+            } else {
+                report.updated.push(scenario_id);
+            }
+            scenario.datapool = definition.datapool;
+            scenario.volumemodel = definition.volumemodel;
+            scenario.duration = definition.duration;
+            scenario.placement = definition.placement;
+            if scenario.ramp != definition.ramp {
+                scenario.spawn_limiter = definition.ramp.spawn_limiter();
+                scenario.ramp = definition.ramp;
+            }
+        }
+        for scenario_id in existing.into_values().flatten() {
+            self.remove_scenario(scenario_id);
+            self.spec_scenario_ids.remove(&scenario_id);
+            report.removed.push(scenario_id);
+        }
+
+        self.refresh_required();
         println!(
-            "Added scenario id={} journey_spec={} datapool={} volumemodel={}",
-            scenario_id, journey_spec_debug, datapool_debug, volumemodel_debug
+            "Reloaded scenarios: added {:?} removed {:?} updated {:?} unchanged {:?}",
+            report.added, report.removed, report.updated, report.unchanged
         );
+        Ok(report)
     }
 
-    pub fn update_required_and_period(&mut self, start_of_period: u64, end_of_period: u64) {
-        let mut required = HashMap::new();
-        // let mut scenarios = self.scenarios.clone();
-        // for (scenario_id, scenario) in scenarios.iter_mut() {
-        for scenerio in self.scenarios.iter_mut() {
-            let scenario_id = scenerio.0.clone();
-            let volume_model = &scenerio.1.volumemodel;
-
-            // println!("volume_model: {}", volume_model);
-            // println!("start_of_period: {}", start_of_period);
-            // println!("end_of_period: {}", end_of_period);
-            // required.insert(1, 1);
-            // println!("scenerio: {:?}", scenerio);
-
-            // let path = Path::new("/home/ryan/dev/mite-controller-rust");
-            // let py_app = fs::read_to_string(path.join("t.py"));
-            let from_python = Python::with_gil(|py| -> PyResult<Py<PyAny>> {
-                let vm_result = volume_model.call1(py, (start_of_period, end_of_period))?;
-                // [TODO] only insert if vm_result is `Ok`, if a Python exception is raised, we should remove the scenario
-                //        from self.scenarios
-                // println!("scenario_id: {} vm_result: {}", scenario_id, vm_result);
-                required.insert(scenario_id, vm_result.extract::<u64>(py)? as i32);
-                Ok(vm_result)
-            });
+    /// Re-evaluate the volume models for the current period, so scenarios
+    /// that were just added, removed or changed apply as soon as the
+    /// evaluator gets to them rather than from the next period.
+    pub fn refresh_required(&mut self) {
+        self.invalidate_evaluations();
+        let now = self.now();
+        if !self.in_start && now < self.current_period_end {
+            self.request_evaluation(self.current_period_start, self.current_period_end);
+        }
+    }
+
+    /// Whether reloading `definition` over `scenario` leaves its settings as
+    /// they were. Scenarios with Python volume models always count as
+    /// updated, since the new model can't be compared with the old.
+    fn unchanged(scenario: &Scenario, definition: &ScenarioDefinition) -> bool {
+        let datapool = match (&scenario.datapool, &definition.datapool) {
+            (Some(datapool), Some(other)) => datapool.same_as(other),
+            (datapool, other) => datapool.is_none() && other.is_none(),
+        };
+        datapool
+            && scenario.volumemodel.same_as(&definition.volumemodel)
+            && scenario.duration == definition.duration
+            && scenario.placement == definition.placement
+            && scenario.ramp == definition.ramp
+    }
 
-            // match scenario.volumemodel(start_of_period, end_of_period) {
-            //     Ok(number) => {
-            //         required.insert(*scenario_id, number as u64);
-            //     }
-            //     Err(_) => {
-            //         println!(
-            //             "Removed scenario {} because volume model raised StopVolumeModel",
-            //             scenario_id
-            //         );
-            //         self.scenarios.remove(scenario_id);
-            //         if self.scenarios.is_empty() {
-            //             println!("All scenarios have been removed from scenario tracker");
-            //         }
-            //     }
-            // }
-        }
-        self.current_period_end = end_of_period;
+    fn update_required_and_period(&mut self, evaluation: Evaluation) {
+        // a refresh of the current period doesn't give a fresh max_step
+        if evaluation.end != self.current_period_end {
+            for scenario in self.scenarios.values_mut() {
+                scenario.spawned_this_period = 0;
+            }
+        }
+        for (scenario_id, e) in evaluation.finished {
+            if self.debug {
+                eprintln!("Volume model for scenario {} finished: {}", scenario_id, e);
+            }
+            let scenario = match self.scenarios.remove(&scenario_id) {
+                Some(scenario) => scenario,
+                None => continue,
+            };
+            match scenario.volumemodel {
+                #[cfg(feature = "python")]
+                VolumeModel::Python(_) => println!(
+                    "Removed scenario {} because volume model raised StopVolumeModel",
+                    scenario_id
+                ),
+                VolumeModel::Native(_) => {
+                    println!("Removed scenario {} because its duration elapsed", scenario_id)
+                }
+            }
+            if self.scenarios.is_empty() {
+                println!("All scenarios have been removed from scenario tracker");
+            }
+        }
+        self.current_period_start = evaluation.start;
+        self.current_period_end = evaluation.end;
 
         // println!("required: {:?}", required);
 
-        self.required = required;
+        self.base_required = evaluation.required;
+        // a scenario may have been removed while it was being evaluated
+        self.base_required
+            .retain(|scenario_id, _| self.scenarios.contains_key(scenario_id));
+        self.apply_multipliers();
+    }
+
+    fn apply_multipliers(&mut self) {
+        self.required = self.multiplied(&self.base_required);
+        self.timeline.record(
+            self.current_period_start,
+            self.current_period_end,
+            &self.required,
+            self.phase.as_ref().map(|phase| phase.name.as_str()),
+        );
+    }
+
+    fn multiplied(&self, base_required: &HashMap<i32, i32>) -> HashMap<i32, i32> {
+        base_required
+            .iter()
+            .map(|(scenario_id, volume)| {
+                let multiplier = self.global_multiplier
+                    * self.scenario_multipliers.get(scenario_id).unwrap_or(&1.0);
+                let volume = (*volume as f64 * multiplier).round().clamp(0.0, MAX_VOLUME);
+                (*scenario_id, volume as i32)
+            })
+            .collect()
+    }
+
+    /// Required and achieved volumes per scenario for the periods ending
+    /// after scenario time `since`, followed by the periods evaluated ahead,
+    /// with the current multipliers applied.
+    pub fn get_timeline(&self, since: u64) -> BTreeMap<i32, Vec<PeriodVolume>> {
+        let mut timeline = self.timeline.since(since);
+        for evaluation in self.upcoming.iter() {
+            for (scenario_id, volume) in self.multiplied(&evaluation.required) {
+                timeline
+                    .entry(scenario_id)
+                    .or_default()
+                    .push(PeriodVolume::planned(evaluation.start, evaluation.end, volume));
+            }
+        }
+        timeline
+    }
+
+    /// Every period's required and achieved volumes so far.
+    pub fn get_history(&self) -> Timeline {
+        self.timeline.clone()
     }
 
-    pub fn get_python_scenario(
+    /// Scale the volume models' output, for every scenario or just one.
+    /// Takes effect immediately rather than at the next period. Multipliers
+    /// outside 0 to `MAX_MULTIPLIER` are rejected.
+    pub fn set_multiplier(
         &mut self,
-        scenario_spec: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // scenario_spec is in the format module:scenario_function_name
-        // first split on the colon into module and function name variables
-        let split: Vec<&str> = scenario_spec.split(":").collect();
-        let module = split[0];
-        let function_name = split[1];
-
-        // [TODO]
-        let path = Path::new("/Users/rli14/identity/mite-controller-rust");
-        let py_app = fs::read_to_string(path.join("t.py"))?;
-        let from_python = Python::with_gil(|py| -> PyResult<Py<PyAny>> {
-            // let syspath: &PyList = py.import("sys")?.getattr("path")?.downcast()?;
-            // syspath.insert(0, &path)?;
-            let app: Py<PyAny> = PyModule::from_code(py, &py_app, "", "")?
-                .getattr(function_name)?
-                .into();
-            let generator = app.call0(py)?;
-            loop {
-                match generator.call_method0(py, "__next__") {
-                    Ok(value) => {
-                        let tuple: &PyTuple = value.extract(py)?;
-                        let journey_spec: Py<PyAny> = tuple.get_item(0)?.into();
-                        let datapool: Py<PyAny> = tuple.get_item(1)?.into();
-                        let volumemodel: Py<PyAny> = tuple.get_item(2)?.into();
-
-                        self.add_scenario(journey_spec, datapool, volumemodel);
-
-                        // println!("journey_spec: {}, datapool: {}, volumemodel: {}", journey_spec, datapool, volumemodel);
-                    }
-                    Err(e) => {
-                        // if self.debug {
-                        //     eprintln!("Exiting scenario generator: {}", e);
-                        // }
-                        break;
-                    }
-                }
+        scenario_id: Option<i32>,
+        multiplier: f64,
+    ) -> Result<(), String> {
+        if !(0.0..=MAX_MULTIPLIER).contains(&multiplier) {
+            return Err(format!(
+                "Multiplier {} is not between 0 and {}",
+                multiplier, MAX_MULTIPLIER
+            ));
+        }
+        match scenario_id {
+            Some(scenario_id) => {
+                self.scenario_multipliers.insert(scenario_id, multiplier);
             }
-            Ok(generator)
+            None => self.global_multiplier = multiplier,
+        }
+        self.multiplier_changes.push(MultiplierChange {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            scenario_time: self._now(),
+            scenario_id,
+            multiplier,
         });
+        println!(
+            "Volume multiplier for {} set to {}",
+            scenario_id.map_or("all scenarios".to_string(), |id| format!("scenario {}", id)),
+            multiplier
+        );
+        self.apply_multipliers();
+        Ok(())
+    }
+
+    pub fn get_multipliers(&self) -> (f64, HashMap<i32, f64>) {
+        (self.global_multiplier, self.scenario_multipliers.clone())
+    }
+
+    pub fn get_multiplier_changes(&self) -> Vec<MultiplierChange> {
+        self.multiplier_changes.clone()
+    }
+
+    /// Work `runner_work` should shed so the fleet stops exceeding the
+    /// required volume. A scenario that is no longer required at all (e.g.
+    /// retired) is shed completely. Each runner gives up its proportional
+    /// share of the excess, rounded up, so repeated requests converge.
+    pub fn get_reductions(
+        &self,
+        current_work: &HashMap<i32, i32>,
+        runner_work: &HashMap<i32, i32>,
+    ) -> HashMap<i32, i32> {
+        if self.is_paused() {
+            return runner_work.clone();
+        }
+        let mut reductions = HashMap::new();
+        for (scenario_id, runner_volume) in runner_work.iter() {
+            let total = *current_work.get(scenario_id).unwrap_or(runner_volume);
+            let required = *self.required.get(scenario_id).unwrap_or(&0);
+            let excess = total - required;
+            if excess <= 0 || *runner_volume <= 0 || total <= 0 {
+                continue;
+            }
+            let share = (excess as f64 * *runner_volume as f64 / total as f64).ceil() as i32;
+            reductions.insert(*scenario_id, share.min(*runner_volume));
+        }
+        reductions
+    }
+
+    /// Load the test's own scenario spec, its scenarios and phases, which
+    /// `reload` re-reads later.
+    pub fn load_spec(
+        &mut self,
+        source: &dyn ScenarioSource,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let scenario_ids = self.load_scenarios(source)?;
+        self.spec_scenario_ids.extend(scenario_ids);
+        self.load_phases(source)
+    }
 
-        // [TODO] there's no reason we need to return a `Result` here?
+    /// Queue up the phases `source` defines and start the first, unless
+    /// phases are already running.
+    pub fn load_phases(
+        &mut self,
+        source: &dyn ScenarioSource,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let phases = source.load_phases()?;
+        if self.debug {
+            println!("Loaded {} phases", phases.len());
+        }
+        self.phases.extend(phases);
+        if self.phase.is_none() && !self.phases.is_empty() {
+            let at = if self.in_start { 0 } else { self._now() };
+            self.start_next_phase(at);
+        }
         Ok(())
     }
 
+    /// The phase running now, if the test has phases.
+    pub fn current_phase(&self) -> Option<String> {
+        self.phase.as_ref().map(|phase| phase.name.clone())
+    }
+
+    /// Phase changes since the last call, to be announced.
+    pub fn take_phase_changes(&mut self) -> Vec<PhaseChange> {
+        let changes = self.phase_changes[self.phase_changes_taken..].to_vec();
+        self.phase_changes_taken = self.phase_changes.len();
+        changes
+    }
+
+    pub fn get_phase_changes(&self) -> Vec<PhaseChange> {
+        self.phase_changes.clone()
+    }
+
+    /// Add every scenario `source` yields, returning their ids.
+    pub fn load_scenarios(
+        &mut self,
+        source: &dyn ScenarioSource,
+    ) -> Result<Vec<i32>, Box<dyn std::error::Error>> {
+        let definitions = source.load()?;
+        if self.debug {
+            println!("Loaded {} scenarios", definitions.len());
+        }
+        Ok(definitions
+            .into_iter()
+            .map(|definition| self.add_scenario(definition))
+            .collect())
+    }
+
     pub fn checkin_data(&mut self, ids: Vec<(i32, i32)>) {
         for id in ids {
             let scenario_id = id.0;
             let scenario_data_id = id.1;
-            if self.scenarios.contains_key(&scenario_id) {
+            if let Some(Some(datapool)) = self.scenarios.get(&scenario_id).map(|s| &s.datapool) {
                 // self.scenarios[scenario_id].datapool.checkin(scenario_data_id);
+                if self.debug {
+                    println!(
+                        "checkin data id {} for datapool {}",
+                        scenario_data_id, datapool
+                    );
+                }
             }
         }
     }
 
+    /// The period of scenario time the current volumes are required for.
+    pub fn current_period(&self) -> (u64, u64) {
+        (self.current_period_start, self.current_period_end)
+    }
+
+    pub fn get_required(&self) -> HashMap<i32, i32> {
+        self.required.clone()
+    }
+
+    /// Stop handing out work and have runners shed everything they hold.
+    /// Scenarios are not retired and phases don't change while paused;
+    /// without `exclude_paused`, on resume the test catches up with wherever
+    /// scenario time has got to.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_some() {
+            return;
+        }
+        self.paused_at = Some(Instant::now());
+        println!("Paused at scenario time {}", self._now());
+    }
+
+    /// Resume a paused test. With `exclude_paused` the paused interval is
+    /// left out of scenario time, so the volume models carry on from the
+    /// point the test was paused at and the periods evaluated while paused
+    /// are forgotten.
+    pub fn resume(&mut self, exclude_paused: bool) {
+        let paused_for = match self.paused_at.take() {
+            Some(paused_at) => paused_at.elapsed(),
+            None => return,
+        };
+        if exclude_paused {
+            self.excluded += paused_for;
+            let now = self.now();
+            let (start, end) = period_containing(now, self.period);
+            self.timeline.rewind(start);
+            self.current_period_start = start;
+            self.current_period_end = end;
+            self.refresh_required();
+        }
+        println!(
+            "Resumed after {}s at scenario time {}",
+            paused_for.as_secs(),
+            self._now()
+        );
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
     pub fn is_active(&self) -> bool {
-        self.in_start || !self.scenarios.is_empty()
+        self.in_start || !self.scenarios.is_empty() || !self.phases.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::scenario_source::JsonSource;
+    use crate::controller::volume_model::NativeVolumeModel;
+    use std::thread;
+
+    fn constant(journey_spec: &str, volume: i32, duration: Option<u64>) -> ScenarioDefinition {
+        ScenarioDefinition {
+            journey_spec: journey_spec.to_string(),
+            datapool: None,
+            volumemodel: VolumeModel::Native(NativeVolumeModel::Constant { volume }),
+            duration,
+            placement: Placement::default(),
+            ramp: RampLimits::default(),
+        }
+    }
+
+    /// Move the manager's monotonic clock `seconds` forward.
+    fn advance(manager: &mut ScenarioManager, seconds: u64) {
+        let by = Duration::from_secs(seconds);
+        manager.origin -= by;
+        if let Some(paused_at) = manager.paused_at.as_mut() {
+            *paused_at -= by;
+        }
+    }
+
+    /// The required volumes once the evaluator thread has caught up.
+    fn settle(manager: &mut ScenarioManager) -> HashMap<i32, i32> {
+        for _ in 0..20 {
+            manager.get_required_work();
+            thread::sleep(Duration::from_millis(5));
+        }
+        manager.get_required_work()
+    }
+
+    fn runner(labels: &[&str], fleet: &[&[&str]]) -> RunnerRequest {
+        let strings = |labels: &[&str]| labels.iter().map(|label| label.to_string()).collect();
+        RunnerRequest {
+            work: HashMap::new(),
+            weight: 1.0,
+            fleet: fleet.iter().map(|runner| (1.0, strings(runner))).collect(),
+            self_limit: None,
+            labels: strings(labels),
+        }
+    }
+
+    #[test]
+    fn periods_tile_scenario_time() {
+        assert_eq!(period_containing(0, 5), (0, 5));
+        assert_eq!(period_containing(4, 5), (0, 5));
+        assert_eq!(period_containing(5, 5), (5, 10));
+        assert_eq!(period_containing(17, 5), (15, 20));
+        assert_eq!(period_containing(17, 1), (17, 18));
+    }
+
+    #[test]
+    fn lookahead_skips_periods_missed_while_idle() {
+        let mut manager = ScenarioManager::new(10, 0, 1000, false);
+        manager.add_scenario(constant("t:j", 2, None));
+        settle(&mut manager);
+        assert_eq!(manager.current_period_end, 10);
+        assert_eq!(manager.requested_until, Some(20));
+
+        // nobody asks for work from 0 until 45
+        advance(&mut manager, 45);
+        settle(&mut manager);
+        assert_eq!(
+            (manager.current_period_start, manager.current_period_end),
+            (40, 50)
+        );
+        assert_eq!(manager.requested_until, Some(60));
+    }
+
+    #[test]
+    fn scenario_time_starts_when_the_delay_ends() {
+        let mut manager = ScenarioManager::new(1, 5, 1000, false);
+        manager.add_scenario(constant("t:j", 2, Some(2)));
+        assert!(manager.is_waiting());
+        assert_eq!(manager.starts_in(), Some(5));
+        // the first period is evaluated during the delay but not required yet
+        assert!(settle(&mut manager).is_empty());
+        assert_eq!(manager.requested_until, Some(1));
+
+        advance(&mut manager, 5);
+        assert_eq!(manager.get_required_work(), HashMap::from([(0, 2)]));
+        assert!(!manager.is_waiting());
+        assert_eq!(manager.now(), 0);
+        assert_eq!(manager.scenarios[&0].started_at, 0);
+
+        // the scenario's duration counts from the end of the delay
+        advance(&mut manager, 1);
+        settle(&mut manager);
+        assert!(manager.scenarios.contains_key(&0));
+        advance(&mut manager, 1);
+        settle(&mut manager);
+        assert!(manager.scenarios.is_empty());
+    }
+
+    fn seeded(allocation: Allocation, seed: u64) -> ScenarioManager {
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        manager.set_allocation(allocation, Some(seed));
+        for journey_spec in ["t:a", "t:b", "t:c"] {
+            manager.add_scenario(constant(journey_spec, 1, None));
+        }
+        manager
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_allocation() {
+        let diff = HashMap::from([(0, 50), (1, 30), (2, 20)]);
+        for allocation in [Allocation::Shuffle, Allocation::Deficit] {
+            let mut first = seeded(allocation, 7);
+            let mut second = seeded(allocation, 7);
+            for _ in 0..5 {
+                assert_eq!(first.allocate(&diff, 40), second.allocate(&diff, 40));
+            }
+        }
+    }
+
+    #[test]
+    fn tight_limits_starve_no_scenario() {
+        let diff = HashMap::from([(0, 1000), (1, 10), (2, 10)]);
+
+        // shuffle hands out a unit per scenario per round
+        let mut manager = seeded(Allocation::Shuffle, 1);
+        for _ in 0..20 {
+            let mut allocated = manager.allocate(&diff, 3);
+            allocated.sort();
+            assert_eq!(allocated, vec![0, 1, 2]);
+        }
+
+        // deficit favours the biggest deficit but still reaches the others
+        let mut manager = seeded(Allocation::Deficit, 1);
+        let mut counts: HashMap<i32, usize> = HashMap::new();
+        for _ in 0..500 {
+            for scenario_id in manager.allocate(&diff, 3) {
+                *counts.entry(scenario_id).or_insert(0) += 1;
+            }
+        }
+        assert!(counts[&0] > counts[&1] && counts[&0] > counts[&2]);
+        assert!(counts.get(&1).is_some_and(|count| *count > 0));
+        assert!(counts.get(&2).is_some_and(|count| *count > 0));
+    }
+
+    #[test]
+    fn share_counts_only_runners_placement_allows() {
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        let mut pinned = constant("t:dmz", 100, None);
+        pinned.placement.required_labels = vec!["dmz".to_string()];
+        manager.add_scenario(pinned);
+        manager.add_scenario(constant("t:j", 40, None));
+        settle(&mut manager);
+
+        let fleet: &[&[&str]] = &[&["dmz"], &[], &[], &[]];
+        let (work, _) = manager.get_work(HashMap::new(), &runner(&["dmz"], fleet));
+        // all of the pinned scenario and a quarter of the other
+        assert_eq!(work.len(), 110);
+        let held = HashMap::from([(0, 100), (1, 10)]);
+        let (work, _) = manager.get_work(held, &runner(&[], fleet));
+        assert_eq!(work.len(), 10);
+        assert!(work.iter().all(|(scenario_id, _, _, _)| *scenario_id == 1));
+    }
+
+    #[test]
+    fn reload_compares_scenarios_by_value() {
+        let source = |volume: u32| {
+            let payload = format!(
+                r#"{{"scenario": [{{"journey": "t:j", "volume_model":
+                    {{"type": "constant", "volume": {}}}, "labels": ["dmz"], "max_step": 5}}]}}"#,
+                volume
+            );
+            JsonSource::parse(&payload).unwrap()
+        };
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        assert_eq!(manager.reload(&source(10)).unwrap().added, vec![0]);
+        assert_eq!(manager.reload(&source(10)).unwrap().unchanged, vec![0]);
+        assert_eq!(manager.reload(&source(20)).unwrap().updated, vec![0]);
+    }
+
+    #[test]
+    fn reload_leaves_scenarios_added_at_runtime() {
+        let spec = JsonSource::parse(
+            r#"{"scenario": [{"journey": "t:j", "volume_model": {"type": "constant", "volume": 1}},
+                {"journey": "t:k", "volume_model": {"type": "constant", "volume": 1}}]}"#,
+        )
+        .unwrap();
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        manager.load_spec(&spec).unwrap();
+        let added = manager.add_scenario(constant("t:j", 5, None));
+        let overlay = JsonSource::parse(
+            r#"{"scenario": [{"journey": "t:l", "volume_model":
+                {"type": "constant", "volume": 1}}]}"#,
+        )
+        .unwrap();
+        let loaded = manager.load_scenarios(&overlay).unwrap();
+
+        let report = manager.reload(&spec).unwrap();
+        assert_eq!(report.unchanged, vec![0, 1]);
+        assert!(report.added.is_empty() && report.removed.is_empty());
+        assert!(manager.scenarios.contains_key(&added));
+        assert!(manager.scenarios.contains_key(&loaded[0]));
+    }
+
+    #[test]
+    fn max_step_holds_across_refreshes() {
+        let mut manager = ScenarioManager::new(100, 0, 1000, false);
+        let mut stepped = constant("t:j", 10, None);
+        stepped.ramp.max_step = Some(2);
+        manager.add_scenario(stepped);
+        settle(&mut manager);
+        let fleet: &[&[&str]] = &[&[]];
+        let (work, _) = manager.get_work(HashMap::new(), &runner(&[], fleet));
+        assert_eq!(work.len(), 2);
+
+        // adding a scenario re-evaluates the current period
+        manager.add_scenario(constant("t:k", 1, None));
+        manager.refresh_required();
+        settle(&mut manager);
+        let held = HashMap::from([(0, 2)]);
+        let (work, _) = manager.get_work(held.clone(), &runner(&[], fleet));
+        assert!(work.iter().all(|(scenario_id, _, _, _)| *scenario_id != 0));
+
+        advance(&mut manager, 100);
+        settle(&mut manager);
+        let (work, _) = manager.get_work(held, &runner(&[], fleet));
+        let stepped = work.iter().filter(|(scenario_id, _, _, _)| *scenario_id == 0);
+        assert_eq!(stepped.count(), 2);
+    }
+
+    #[test]
+    fn timeline_includes_planned_periods() {
+        let mut manager = ScenarioManager::new(10, 0, 1000, false);
+        manager.set_lookahead(2);
+        manager.add_scenario(constant("t:j", 4, None));
+        settle(&mut manager);
+        manager.set_multiplier(None, 2.0).unwrap();
+
+        let periods: Vec<(u64, i32)> = manager.get_timeline(0)[&0]
+            .iter()
+            .map(|period| (period.start, period.required))
+            .collect();
+        // the current period is re-recorded rather than added again
+        assert_eq!(periods, vec![(0, 8), (10, 8), (20, 8)]);
+        assert_eq!(manager.get_history().scenarios[&0].len(), 1);
+        assert!(manager.get_timeline(10)[&0].iter().all(|period| period.start >= 10));
+    }
+
+    #[test]
+    fn multipliers_are_bounded() {
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        manager.add_scenario(constant("t:j", i32::MAX, None));
+        manager.add_scenario(constant("t:k", i32::MAX, None));
+        assert!(manager.set_multiplier(None, 1e9).is_err());
+        assert!(manager.set_multiplier(None, -1.0).is_err());
+        assert!(manager.set_multiplier(Some(0), f64::NAN).is_err());
+        assert_eq!(manager.get_multiplier_changes().len(), 0);
+
+        manager.set_multiplier(None, MAX_MULTIPLIER).unwrap();
+        let required = settle(&mut manager);
+        assert_eq!(required[&0], MAX_VOLUME as i32);
+        assert_eq!(required.values().sum::<i32>(), 2 * MAX_VOLUME as i32);
+    }
+
+    #[test]
+    fn pause_holds_scenario_durations() {
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        manager.add_scenario(constant("t:j", 2, Some(4)));
+        assert_eq!(settle(&mut manager), HashMap::from([(0, 2)]));
+
+        advance(&mut manager, 2);
+        settle(&mut manager);
+        manager.pause();
+        advance(&mut manager, 5);
+        settle(&mut manager);
+        assert!(manager.scenarios.contains_key(&0));
+        assert!(manager.is_active());
+
+        manager.resume(true);
+        assert_eq!(manager.now(), 2);
+        assert_eq!(settle(&mut manager), HashMap::from([(0, 2)]));
+
+        advance(&mut manager, 2);
+        settle(&mut manager);
+        assert!(manager.scenarios.is_empty());
+    }
+
+    #[test]
+    fn pause_holds_max_duration_and_phases() {
+        let mut manager = ScenarioManager::new(1, 0, 1000, false);
+        manager.set_max_duration(6);
+        manager.phases.extend([
+            PhaseDefinition {
+                name: "ramp".to_string(),
+                duration: Some(3),
+                scenarios: vec![constant("t:j", 1, None)],
+            },
+            PhaseDefinition {
+                name: "peak".to_string(),
+                duration: None,
+                scenarios: vec![constant("t:j", 5, None)],
+            },
+        ]);
+        manager.start_next_phase(0);
+        settle(&mut manager);
+
+        advance(&mut manager, 1);
+        settle(&mut manager);
+        manager.pause();
+        advance(&mut manager, 10);
+        settle(&mut manager);
+        assert_eq!(manager.current_phase().as_deref(), Some("ramp"));
+        assert!(manager.is_active());
+
+        manager.resume(true);
+        advance(&mut manager, 2);
+        settle(&mut manager);
+        assert_eq!(manager.current_phase().as_deref(), Some("peak"));
+
+        advance(&mut manager, 3);
+        settle(&mut manager);
+        assert_eq!(manager.current_phase(), None);
+        assert!(!manager.is_active());
     }
 }
@@ -0,0 +1,189 @@
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+#[cfg(feature = "python")]
+use pyo3::types::{PyDict, PyTuple};
+#[cfg(feature = "python")]
+use std::fs;
+#[cfg(feature = "python")]
+use std::path::Path;
+
+use super::scenario_file::{self, ScenarioEntry, ScenarioFile};
+use super::scenario_manager::{PhaseDefinition, ScenarioDefinition};
+#[cfg(feature = "python")]
+use super::scenario_manager::{Placement, RampLimits};
+#[cfg(feature = "python")]
+use super::volume_model::{Datapool, VolumeModel};
+
+/// Somewhere the scenarios of a test come from.
+pub trait ScenarioSource {
+    /// The scenarios as they stand now. Called at startup and again on every
+    /// reload, so sources that can change should be re-read each time.
+    fn load(&self) -> Result<Vec<ScenarioDefinition>, Box<dyn std::error::Error>>;
+
+    /// The test's phases, in the order they run. Only read at startup.
+    fn load_phases(&self) -> Result<Vec<PhaseDefinition>, Box<dyn std::error::Error>> {
+        Ok(Vec::new())
+    }
+}
+
+/// A scenario generator function in a Python module, `module:function`.
+#[cfg(feature = "python")]
+pub struct PythonSource {
+    module: String,
+    function_name: String,
+}
+
+#[cfg(feature = "python")]
+impl PythonSource {
+    pub fn parse(scenario_spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        // scenario_spec is in the format module:scenario_function_name
+        match scenario_spec.split(':').collect::<Vec<&str>>()[..] {
+            [module, function_name] => Ok(Self {
+                module: module.to_string(),
+                function_name: function_name.to_string(),
+            }),
+            _ => Err(format!("Invalid scenario spec {}", scenario_spec).into()),
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+impl ScenarioSource for PythonSource {
+    fn load(&self) -> Result<Vec<ScenarioDefinition>, Box<dyn std::error::Error>> {
+        let path = format!("{}.py", self.module.replace('.', "/"));
+        let py_app = fs::read_to_string(Path::new(&path))?;
+        let definitions = Python::with_gil(|py| -> PyResult<Vec<ScenarioDefinition>> {
+            // let syspath: &PyList = py.import("sys")?.getattr("path")?.downcast()?;
+            // syspath.insert(0, &path)?;
+            let app: Py<PyAny> = PyModule::from_code(py, &py_app, "", "")?
+                .getattr(self.function_name.as_str())?
+                .into();
+            let generator = app.call0(py)?;
+            let mut definitions = Vec::new();
+            // ends on StopIteration, while any other exception is an error
+            for value in generator.as_ref(py).iter()? {
+                let tuple: &PyTuple = value?.extract()?;
+                let (placement, ramp, duration) = match tuple.len() {
+                    4 => {
+                        let options = tuple.get_item(3)?;
+                        let duration = match options.downcast::<PyDict>()?.get_item("duration")? {
+                            Some(duration) => duration.extract()?,
+                            None => None,
+                        };
+                        (
+                            Placement::from_python(options)?,
+                            RampLimits::from_python(options)?,
+                            duration,
+                        )
+                    }
+                    _ => (Placement::default(), RampLimits::default(), None),
+                };
+                definitions.push(ScenarioDefinition {
+                    journey_spec: tuple.get_item(0)?.str()?.to_string(),
+                    datapool: Some(Datapool::Python(tuple.get_item(1)?.into())),
+                    volumemodel: VolumeModel::Python(tuple.get_item(2)?.into()),
+                    duration,
+                    placement,
+                    ramp,
+                });
+            }
+            Ok(definitions)
+        })?;
+        Ok(definitions)
+    }
+}
+
+/// A TOML or YAML scenario file.
+pub struct FileSource {
+    path: String,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl ScenarioSource for FileSource {
+    fn load(&self) -> Result<Vec<ScenarioDefinition>, Box<dyn std::error::Error>> {
+        Ok(scenario_file::load(&self.path)?.scenario_definitions())
+    }
+
+    fn load_phases(&self) -> Result<Vec<PhaseDefinition>, Box<dyn std::error::Error>> {
+        Ok(scenario_file::load(&self.path)?.phase_definitions())
+    }
+}
+
+/// Scenarios given inline, in the same shape as a scenario file.
+pub struct JsonSource {
+    file: ScenarioFile,
+}
+
+impl JsonSource {
+    pub fn new(scenarios: Vec<ScenarioEntry>) -> Self {
+        Self {
+            file: ScenarioFile {
+                scenarios,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Parse `{"scenario": [...], "phase": [...]}`.
+    pub fn parse(payload: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            file: serde_json::from_str(payload)?,
+        })
+    }
+}
+
+impl ScenarioSource for JsonSource {
+    fn load(&self) -> Result<Vec<ScenarioDefinition>, Box<dyn std::error::Error>> {
+        Ok(self.file.scenario_definitions())
+    }
+
+    fn load_phases(&self) -> Result<Vec<PhaseDefinition>, Box<dyn std::error::Error>> {
+        Ok(self.file.phase_definitions())
+    }
+}
+
+/// Pick a source by the scenario spec's scheme: `py:module:function`,
+/// `file:path.toml` or `json:{"scenario": [...]}`. Without a scheme, specs
+/// ending in .toml, .yaml or .yml are files and anything else is Python.
+pub fn from_spec(
+    scenario_spec: &str,
+) -> Result<Box<dyn ScenarioSource>, Box<dyn std::error::Error>> {
+    if let Some(spec) = scenario_spec.strip_prefix("py:") {
+        return python_source(spec);
+    }
+    if let Some(path) = scenario_spec.strip_prefix("file:") {
+        return Ok(Box::new(FileSource::new(path)));
+    }
+    if let Some(payload) = scenario_spec.strip_prefix("json:") {
+        return Ok(Box::new(JsonSource::parse(payload)?));
+    }
+    if scenario_file::is_scenario_file(scenario_spec) {
+        return Ok(Box::new(FileSource::new(scenario_spec)));
+    }
+    python_source(scenario_spec)
+}
+
+#[cfg(feature = "python")]
+fn python_source(
+    scenario_spec: &str,
+) -> Result<Box<dyn ScenarioSource>, Box<dyn std::error::Error>> {
+    Ok(Box::new(PythonSource::parse(scenario_spec)?))
+}
+
+#[cfg(not(feature = "python"))]
+fn python_source(
+    scenario_spec: &str,
+) -> Result<Box<dyn ScenarioSource>, Box<dyn std::error::Error>> {
+    Err(format!(
+        "Cannot load Python scenario spec {} without the python feature",
+        scenario_spec
+    )
+    .into())
+}
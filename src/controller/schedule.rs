@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// When a scheduled test should start, from either an RFC 3339 timestamp,
+/// e.g. `2026-06-01T09:00:00+01:00`, or a cron expression evaluated in UTC,
+/// e.g. `0 9 * * MON-FRI`, which starts at its next occurrence. Cron
+/// expressions may give seconds as a sixth field at the front.
+pub fn parse_start_at(spec: &str) -> Result<SystemTime, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(spec) {
+        return Ok(time.into());
+    }
+    // the cron crate wants seconds, so standard five-field expressions get 0
+    let expression = match spec.split_whitespace().count() {
+        5 => format!("0 {}", spec),
+        _ => spec.to_string(),
+    };
+    let schedule = Schedule::from_str(&expression).map_err(|e| {
+        format!(
+            "{} is neither an RFC 3339 timestamp nor a cron expression: {}",
+            spec, e
+        )
+    })?;
+    schedule
+        .upcoming(Utc)
+        .next()
+        .map(|time: DateTime<Utc>| time.into())
+        .ok_or_else(|| format!("Cron expression {} never fires again", spec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn seconds(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn rfc3339_with_an_offset() {
+        let time = parse_start_at("2026-06-01T09:00:00+01:00").unwrap();
+        assert_eq!(seconds(time), 1_780_300_800);
+    }
+
+    #[test]
+    fn five_field_cron_starts_on_the_minute() {
+        let time = parse_start_at("0 9 * * *").unwrap();
+        assert!(time > SystemTime::now());
+        assert_eq!(seconds(time) % 86_400, 9 * 3600);
+    }
+
+    #[test]
+    fn six_field_cron_takes_seconds() {
+        let time = parse_start_at("30 0 9 * * *").unwrap();
+        assert!(time > SystemTime::now());
+        assert_eq!(seconds(time) % 86_400, 9 * 3600 + 30);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let error = parse_start_at("tomorrow morning").unwrap_err();
+        assert!(error.contains("neither an RFC 3339 timestamp nor a cron expression"));
+        let error = parse_start_at("0 0 0 1 1 * 2000").unwrap_err();
+        assert!(error.contains("never fires again"));
+    }
+}
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// The volume a scenario was required to run over one period, and what the
+/// fleet actually ran.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeriodVolume {
+    /// Scenario time the period starts at.
+    pub start: u64,
+    pub end: u64,
+    pub required: i32,
+    /// Mean of the running work seen at each runner request during the
+    /// period, or unset for periods still to come.
+    pub achieved: Option<f64>,
+    /// The test phase running when the period began, if the test has phases.
+    #[serde(default)]
+    pub phase: Option<String>,
+    #[serde(skip)]
+    samples: u32,
+}
+
+impl PeriodVolume {
+    pub fn planned(start: u64, end: u64, required: i32) -> Self {
+        Self {
+            start,
+            end,
+            required,
+            achieved: None,
+            phase: None,
+            samples: 0,
+        }
+    }
+}
+
+/// Per-scenario history of required against achieved volume, one entry per
+/// period the scenario was live for.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Timeline {
+    pub scenarios: BTreeMap<i32, Vec<PeriodVolume>>,
+}
+
+impl Timeline {
+    /// Set what each scenario in `required` has to run over `[start, end)`,
+    /// during `phase`. Re-recording a period, e.g. after a refresh or a
+    /// multiplier change, updates its entry rather than adding another.
+    pub fn record(
+        &mut self,
+        start: u64,
+        end: u64,
+        required: &HashMap<i32, i32>,
+        phase: Option<&str>,
+    ) {
+        for (scenario_id, volume) in required.iter() {
+            let periods = self.scenarios.entry(*scenario_id).or_default();
+            let period = match periods.last_mut() {
+                Some(last) if last.end == end => last,
+                _ => {
+                    periods.push(PeriodVolume::planned(start, end, *volume));
+                    periods.last_mut().unwrap()
+                }
+            };
+            period.required = *volume;
+            period.phase = phase.map(str::to_string);
+        }
+    }
+
+    /// Fold the fleet's running work into the achieved volume of the period
+    /// ending at `end`.
+    pub fn sample(&mut self, end: u64, actual: &HashMap<i32, i32>) {
+        for (scenario_id, periods) in self.scenarios.iter_mut() {
+            let period = match periods.last_mut() {
+                Some(period) if period.end == end => period,
+                _ => continue,
+            };
+            let volume = *actual.get(scenario_id).unwrap_or(&0) as f64;
+            period.samples += 1;
+            let achieved = period.achieved.unwrap_or(0.0);
+            period.achieved = Some(achieved + (volume - achieved) / period.samples as f64);
+        }
+    }
+
+    /// Forget the periods ending after scenario time `time`, for when
+    /// scenario time is wound back to it.
+    pub fn rewind(&mut self, time: u64) {
+        for periods in self.scenarios.values_mut() {
+            periods.retain(|period| period.end <= time);
+        }
+        self.scenarios.retain(|_, periods| !periods.is_empty());
+    }
+
+    /// Periods ending after scenario time `since`.
+    pub fn since(&self, since: u64) -> BTreeMap<i32, Vec<PeriodVolume>> {
+        let mut timeline = BTreeMap::new();
+        for (scenario_id, periods) in self.scenarios.iter() {
+            let periods: Vec<PeriodVolume> = periods
+                .iter()
+                .filter(|period| period.end > since)
+                .cloned()
+                .collect();
+            if !periods.is_empty() {
+                timeline.insert(*scenario_id, periods);
+            }
+        }
+        timeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_one_entry_per_period() {
+        let mut timeline = Timeline::default();
+        timeline.record(0, 5, &HashMap::from([(0, 10)]), None);
+        // re-recorded, e.g. after a multiplier change
+        timeline.record(0, 5, &HashMap::from([(0, 20)]), Some("peak"));
+        timeline.record(5, 10, &HashMap::from([(0, 30), (1, 1)]), Some("peak"));
+
+        let periods = &timeline.scenarios[&0];
+        assert_eq!(periods.len(), 2);
+        assert_eq!(
+            (periods[0].start, periods[0].end, periods[0].required),
+            (0, 5, 20)
+        );
+        assert_eq!(periods[0].phase.as_deref(), Some("peak"));
+        assert_eq!(periods[1].required, 30);
+        assert_eq!(timeline.scenarios[&1].len(), 1);
+    }
+
+    #[test]
+    fn achieved_is_the_mean_of_the_samples() {
+        let mut timeline = Timeline::default();
+        timeline.record(0, 5, &HashMap::from([(0, 10), (1, 10)]), None);
+        timeline.sample(5, &HashMap::from([(0, 4)]));
+        timeline.sample(5, &HashMap::from([(0, 8)]));
+        // samples for another period are ignored
+        timeline.sample(10, &HashMap::from([(0, 100)]));
+
+        assert_eq!(timeline.scenarios[&0][0].achieved, Some(6.0));
+        assert_eq!(timeline.scenarios[&1][0].achieved, Some(0.0));
+    }
+
+    #[test]
+    fn rewind_and_since_cut_at_period_ends() {
+        let mut timeline = Timeline::default();
+        for start in [0, 5, 10] {
+            timeline.record(start, start + 5, &HashMap::from([(0, 1)]), None);
+        }
+        timeline.record(10, 15, &HashMap::from([(1, 1)]), None);
+
+        let since = timeline.since(5);
+        assert_eq!(
+            since[&0]
+                .iter()
+                .map(|period| period.start)
+                .collect::<Vec<_>>(),
+            [5, 10]
+        );
+        assert_eq!(since[&1].len(), 1);
+        assert!(timeline.since(15).is_empty());
+
+        timeline.rewind(10);
+        assert_eq!(timeline.scenarios[&0].len(), 2);
+        assert!(!timeline.scenarios.contains_key(&1));
+    }
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Limits how many new journey instances are started per second. Tokens
+/// refill continuously at `rate` per second up to `burst`, and each unit of
+/// work handed out takes one. The burst is at least 1, so a rate below one
+/// per second still hands out whole units.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    spawned: u64,
+    throttled_requests: u64,
+}
+
+/// Counters describing the bucket, reported in the controller's status.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpawnMetrics {
+    pub rate: f64,
+    pub burst: f64,
+    pub tokens: f64,
+    /// Units of work handed out since the start of the test.
+    pub spawned: u64,
+    /// Requests for work that got less than they asked for because the
+    /// bucket ran dry. A runner polling while the bucket is empty counts
+    /// once per poll, so this says how often the limit bit, not by how much.
+    pub throttled_requests: u64,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        let burst = burst.max(1.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            spawned: 0,
+            throttled_requests: 0,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Whole units that may be started right now.
+    pub fn available(&mut self) -> usize {
+        self.refill();
+        self.tokens.floor().max(0.0) as usize
+    }
+
+    /// Take `granted` tokens for work handed out to a request that wanted
+    /// `wanted` units.
+    pub fn take(&mut self, granted: usize, wanted: usize) {
+        self.tokens -= granted as f64;
+        self.spawned += granted as u64;
+        if granted < wanted {
+            self.throttled_requests += 1;
+        }
+    }
+
+    pub fn set_burst(&mut self, burst: f64) {
+        self.burst = burst.max(1.0);
+        self.tokens = self.tokens.min(burst);
+    }
+
+    pub fn metrics(&self) -> SpawnMetrics {
+        SpawnMetrics {
+            rate: self.rate,
+            burst: self.burst,
+            tokens: self.tokens,
+            spawned: self.spawned,
+            throttled_requests: self.throttled_requests,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Move the bucket's last refill `seconds` into the past.
+    fn wait(bucket: &mut TokenBucket, seconds: f64) {
+        bucket.last_refill -= Duration::from_secs_f64(seconds);
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let mut bucket = TokenBucket::new(2.0, 4.0);
+        assert_eq!(bucket.available(), 4);
+        bucket.take(4, 4);
+        assert_eq!(bucket.available(), 0);
+        wait(&mut bucket, 1.0);
+        assert_eq!(bucket.available(), 2);
+        wait(&mut bucket, 10.0);
+        assert_eq!(bucket.available(), 4);
+    }
+
+    #[test]
+    fn set_burst_caps_the_tokens() {
+        let mut bucket = TokenBucket::new(2.0, 10.0);
+        bucket.set_burst(3.0);
+        assert_eq!(bucket.available(), 3);
+        bucket.set_burst(6.0);
+        wait(&mut bucket, 10.0);
+        assert_eq!(bucket.available(), 6);
+        bucket.set_burst(0.0);
+        assert_eq!(bucket.metrics().burst, 1.0);
+    }
+
+    #[test]
+    fn counts_spawned_units_and_throttled_requests() {
+        let mut bucket = TokenBucket::new(1.0, 2.0);
+        bucket.take(2, 2);
+        bucket.take(0, 5);
+        bucket.take(0, 5);
+        let metrics = bucket.metrics();
+        assert_eq!(metrics.spawned, 2);
+        assert_eq!(metrics.throttled_requests, 2);
+    }
+
+    #[test]
+    fn slow_rates_still_hand_out_whole_units() {
+        let mut bucket = TokenBucket::new(0.5, 0.5);
+        assert_eq!(bucket.available(), 1);
+        bucket.take(1, 1);
+        assert_eq!(bucket.available(), 0);
+        wait(&mut bucket, 3.0);
+        assert_eq!(bucket.available(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use zmq::{Context, Socket, DONTWAIT, PUSH, REP};
+
+/// Request/reply channel the controller serves runners over.
+///
+/// Every request returned by `recv` must be answered by exactly one `send`
+/// before the next request is received.
+pub trait Transport {
+    /// Poll for the next request without blocking.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+
+    /// Reply to the request most recently returned by `recv`.
+    fn send(&mut self, buf: &[u8]);
+
+    /// Whether the transport can no longer deliver requests.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// The zmq REP socket runners connect to in a real deployment.
+pub struct ZmqTransport {
+    _context: Context,
+    socket: Socket,
+}
+
+impl ZmqTransport {
+    pub fn bind(endpoint: &str) -> Result<Self, zmq::Error> {
+        let context = Context::new();
+        let socket = context.socket(REP)?;
+        socket.bind(endpoint)?;
+        Ok(Self {
+            _context: context,
+            socket,
+        })
+    }
+}
+
+impl Transport for ZmqTransport {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.socket.recv_bytes(DONTWAIT).ok()
+    }
+
+    fn send(&mut self, buf: &[u8]) {
+        if let Err(e) = self.socket.send(buf, 0) {
+            eprintln!("Failed to send reply: {}", e);
+        }
+    }
+}
+
+/// PUSH socket the controller announces events on, e.g. phase changes,
+/// connected to the message socket that runners report to.
+pub struct MessageSender {
+    _context: Context,
+    socket: Socket,
+}
+
+impl MessageSender {
+    pub fn connect(endpoint: &str) -> Result<Self, zmq::Error> {
+        let context = Context::new();
+        let socket = context.socket(PUSH)?;
+        socket.connect(endpoint)?;
+        Ok(Self {
+            _context: context,
+            socket,
+        })
+    }
+
+    /// Send without blocking; a message nobody is there to receive is
+    /// dropped rather than holding up the controller.
+    pub fn send(&self, buf: &[u8]) {
+        if let Err(e) = self.socket.send(buf, DONTWAIT) {
+            eprintln!("Failed to send message: {}", e);
+        }
+    }
+}
+
+type Request = (Vec<u8>, Sender<Vec<u8>>);
+
+/// In-process transport, so a controller and any number of simulated
+/// runners can share one process without binding ports.
+pub struct ChannelTransport {
+    requests: Receiver<Request>,
+    pending_reply: Option<Sender<Vec<u8>>>,
+    closed: bool,
+}
+
+/// Runner side of a `ChannelTransport`. Cheap to clone, one per runner.
+#[derive(Clone)]
+pub struct ChannelClient {
+    requests: Sender<Request>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (Self, ChannelClient) {
+        let (tx, rx) = channel();
+        (
+            Self {
+                requests: rx,
+                pending_reply: None,
+                closed: false,
+            },
+            ChannelClient { requests: tx },
+        )
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        // a request that was never answered is dropped, which unblocks its client
+        self.pending_reply = None;
+        match self.requests.try_recv() {
+            Ok((msg, reply)) => {
+                self.pending_reply = Some(reply);
+                Some(msg)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) {
+        if let Some(reply) = self.pending_reply.take() {
+            // the client may have gone away, which is not our problem
+            let _ = reply.send(buf.to_vec());
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl ChannelClient {
+    /// Send a request and block until the controller answers it.
+    /// Returns `None` if the controller dropped the request without a reply.
+    pub fn request(&self, buf: Vec<u8>) -> Option<Vec<u8>> {
+        let (tx, rx) = channel();
+        self.requests.send((buf, tx)).ok()?;
+        rx.recv().ok()
+    }
+}
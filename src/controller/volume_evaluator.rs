@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::volume_model::VolumeModel;
+
+/// Volume models to evaluate over one window of scenario time.
+pub struct EvaluationRequest {
+    /// Bumped whenever the set of scenarios changes, so results computed for
+    /// an outdated set can be told apart and dropped.
+    pub generation: u64,
+    pub start: u64,
+    pub end: u64,
+    /// (scenario id, volume model, scenario time the scenario started at)
+    pub volume_models: Vec<(i32, VolumeModel, u64)>,
+}
+
+/// The volumes required over a window, as computed by the evaluator thread.
+pub struct Evaluation {
+    pub generation: u64,
+    pub start: u64,
+    pub end: u64,
+    pub required: HashMap<i32, i32>,
+    /// Scenarios whose volume model has finished, and why.
+    pub finished: Vec<(i32, String)>,
+    /// How long the volume models took between them.
+    pub elapsed: Duration,
+}
+
+/// Evaluates volume models on a thread of its own, so a slow (e.g. Python)
+/// volume model never holds up a runner request. The thread exits once the
+/// evaluator is dropped.
+pub struct VolumeEvaluator {
+    requests: Sender<EvaluationRequest>,
+    results: Receiver<Evaluation>,
+}
+
+impl Default for VolumeEvaluator {
+    fn default() -> Self {
+        Self::spawn()
+    }
+}
+
+impl VolumeEvaluator {
+    pub fn spawn() -> Self {
+        let (request_tx, request_rx) = channel::<EvaluationRequest>();
+        let (result_tx, result_rx) = channel();
+        thread::spawn(move || {
+            for request in request_rx.iter() {
+                if result_tx.send(Self::evaluate(request)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests: request_tx,
+            results: result_rx,
+        }
+    }
+
+    fn evaluate(request: EvaluationRequest) -> Evaluation {
+        let started = Instant::now();
+        let mut required = HashMap::new();
+        let mut finished = Vec::new();
+        for (scenario_id, volume_model, started_at) in request.volume_models {
+            match volume_model.evaluate(request.start, request.end, started_at) {
+                Ok(volume) => {
+                    required.insert(scenario_id, volume);
+                }
+                Err(e) => finished.push((scenario_id, e)),
+            }
+        }
+        Evaluation {
+            generation: request.generation,
+            start: request.start,
+            end: request.end,
+            required,
+            finished,
+            elapsed: started.elapsed(),
+        }
+    }
+
+    pub fn request(&self, request: EvaluationRequest) {
+        if self.requests.send(request).is_err() {
+            eprintln!("Volume model evaluator has stopped");
+        }
+    }
+
+    /// Every evaluation finished since the last call, oldest first.
+    pub fn results(&self) -> Vec<Evaluation> {
+        self.results.try_iter().collect()
+    }
+}
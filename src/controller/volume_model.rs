@@ -0,0 +1,124 @@
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Decides how many instances of a scenario's journey should be running
+/// over a period.
+///
+/// A model is asked about one period at a time as `(start, end)`, the bounds
+/// of `[start, end)` in whole seconds of scenario time. Scenario time is 0
+/// when the start delay ends and counts on a monotonic clock, less any
+/// paused time left out on resume. `start` is always a multiple of the
+/// period and `end` is `start` plus the period. Periods are asked about in
+/// increasing order, but any nobody needed, e.g. while no runner was asking
+/// for work, are skipped. The same period may be asked about again after
+/// scenarios are added, removed or reloaded, or scenario time is wound back
+/// on resume, so models should not keep state between calls.
+#[derive(Debug, Clone)]
+pub enum VolumeModel {
+    /// A Python callable taking `(start, end)` in scenario time, which
+    /// raises once the scenario is finished.
+    #[cfg(feature = "python")]
+    Python(Py<PyAny>),
+    Native(NativeVolumeModel),
+}
+
+/// Volume models that don't need a Python interpreter. Times are seconds
+/// since the scenario was added. They run until the scenario is retired, by
+/// its own `duration` or otherwise; unknown keys are rejected so a
+/// `duration` given here by mistake isn't silently ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum NativeVolumeModel {
+    /// `volume` instances.
+    Constant { volume: i32 },
+    /// Linear ramp from `from` to `to` over `over` seconds, then holding `to`.
+    Ramp { from: i32, to: i32, over: u64 },
+}
+
+impl NativeVolumeModel {
+    fn evaluate(&self, start: u64) -> i32 {
+        match self {
+            Self::Constant { volume } => *volume,
+            Self::Ramp { from, to, over } => {
+                if start >= *over {
+                    return *to;
+                }
+                let progress = start as f64 / *over as f64;
+                (*from as f64 + (*to - *from) as f64 * progress).round() as i32
+            }
+        }
+    }
+}
+
+impl VolumeModel {
+    /// The volume required over `[start, end)`, or why the scenario is
+    /// finished. `started_at` is the scenario time the scenario was added at,
+    /// which native models count from.
+    #[cfg_attr(not(feature = "python"), allow(unused_variables))]
+    pub fn evaluate(&self, start: u64, end: u64, started_at: u64) -> Result<i32, String> {
+        match self {
+            #[cfg(feature = "python")]
+            Self::Python(volume_model) => Python::with_gil(|py| -> PyResult<i32> {
+                let vm_result = volume_model.call1(py, (start, end))?;
+                Ok(vm_result.extract::<u64>(py)? as i32)
+            })
+            .map_err(|e| e.to_string()),
+            Self::Native(volume_model) => {
+                Ok(volume_model.evaluate(start.saturating_sub(started_at)))
+            }
+        }
+    }
+
+    /// Whether two models are known to give the same volumes. A reload
+    /// always creates new Python objects, which can't be compared, so
+    /// Python models never are.
+    pub fn same_as(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Native(volume_model), Self::Native(other)) => volume_model == other,
+            #[cfg(feature = "python")]
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for VolumeModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "python")]
+            Self::Python(volume_model) => write!(f, "{}", volume_model),
+            Self::Native(volume_model) => write!(f, "{:?}", volume_model),
+        }
+    }
+}
+
+/// Where a scenario's journeys get their data from.
+#[derive(Debug, Clone)]
+pub enum Datapool {
+    #[cfg(feature = "python")]
+    Python(Py<PyAny>),
+    Named(String),
+}
+
+impl Datapool {
+    /// Whether two datapools are known to be the same; as with volume models,
+    /// Python datapools never are.
+    pub fn same_as(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Named(name), Self::Named(other)) => name == other,
+            #[cfg(feature = "python")]
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Datapool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "python")]
+            Self::Python(datapool) => write!(f, "{}", datapool),
+            Self::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use clap::Parser;
 
-mod controller;
-use controller::controller::Controller;
-use controller::scenario_manager::ScenarioManager;
-
-
+use mite_controller_rust::controller::abort::AbortConditions;
+use mite_controller_rust::controller::controller::Controller;
+use mite_controller_rust::controller::scenario_manager::{Allocation, ScenarioManager};
+use mite_controller_rust::controller::scenario_source;
+use mite_controller_rust::controller::schedule::parse_start_at;
+use mite_controller_rust::simulator::{simulate, SimulationConfig};
+use std::time::{Duration, SystemTime};
 
 /// The controller dictates the scenario to run.
 /// It is responsible for distributing work to the runners
@@ -19,7 +21,8 @@ struct Args {
     #[arg(long, default_value = "tcp://0.0.0.0:14301")]
     controller_socket: String,
 
-    /// Scenario spec.
+    /// Scenario spec: `py:module:function`, `file:path.toml` or `json:{...}`.
+    /// Bare `module:function` is Python and a bare .toml/.yaml path a file.
     #[arg()]
     scenario_spec: String,
 
@@ -27,6 +30,12 @@ struct Args {
     #[arg(long, default_value = "0")]
     delay_start_seconds: u64,
 
+    /// Start at this wall-clock time instead, an RFC 3339 timestamp or the
+    /// next occurrence of a cron expression (UTC); runners may connect and
+    /// warm up beforehand
+    #[arg(long, value_parser = parse_start_at, conflicts_with = "delay_start_seconds")]
+    start_at: Option<SystemTime>,
+
     // period
     #[arg(long, default_value = "1")]
     max_loop_delay: u64,
@@ -35,11 +44,89 @@ struct Args {
     #[arg(long, default_value = "1000")]
     spawn_rate: u64,
 
+    /// Most journeys that may start at once across all runners, defaults to
+    /// one second's worth of the spawn rate
+    #[arg(long)]
+    spawn_burst: Option<u64>,
+
+    /// Retire every scenario this many seconds into the test, whatever the
+    /// volume models say
+    #[arg(long)]
+    max_duration: Option<u64>,
+
+    /// How many periods ahead volume models are evaluated
+    #[arg(long, default_value = "1")]
+    lookahead_periods: usize,
+
+    /// Seconds of runner requests the hit rate is measured over
+    #[arg(long, default_value = "10")]
+    hit_rate_window: u64,
+
+    /// Scale every scenario's volume by this much, between 0 and 1000,
+    /// adjustable at runtime
+    #[arg(long, default_value = "1.0")]
+    volume_multiplier: f64,
+
+    /// How work is shared between scenarios when a runner's limit is hit
+    #[arg(long, value_enum, default_value = "shuffle")]
+    allocation: Allocation,
+
+    /// Seed for the allocation order, to make a run reproducible
+    #[arg(long)]
+    allocation_seed: Option<u64>,
+
+    /// Leave time spent paused out of scenario time when resuming on SIGUSR2
+    /// or a resume request that doesn't say
+    #[arg(long)]
+    exclude_paused_time: bool,
+
+    /// Stop the test once no runner has been active for this many seconds
+    #[arg(long)]
+    abort_no_runners_seconds: Option<u64>,
+
+    /// Stop the test once the achieved volume is below this percentage of the
+    /// required volume for --abort-below-achieved-periods periods in a row
+    #[arg(long)]
+    abort_below_achieved_percent: Option<f64>,
+
+    /// Periods in a row the achieved volume must fall short for
+    #[arg(long, default_value = "3")]
+    abort_below_achieved_periods: u32,
+
+    /// Stop the test once an error rate reported over the controller socket
+    /// exceeds this
+    #[arg(long)]
+    abort_error_rate: Option<f64>,
+
+    /// Write the end-of-test report to this path as JSON
+    #[arg(long)]
+    report_path: Option<String>,
+
     /// Debug mode
     #[arg(long)]
     debug: bool,
-}
 
+    /// Rehearse the scenario against this many in-process fake runners
+    /// instead of serving real ones
+    #[arg(long, default_value = "0")]
+    simulate_runners: usize,
+
+    /// How long a simulated journey takes, in milliseconds
+    #[arg(long, default_value = "1000")]
+    simulate_latency_ms: u64,
+
+    /// How often a simulated runner requests work, in milliseconds
+    #[arg(long, default_value = "100")]
+    simulate_request_interval_ms: u64,
+
+    /// How often the simulation reports required and actual volumes, in seconds
+    #[arg(long, default_value = "1")]
+    simulate_report_seconds: u64,
+
+    /// Limit on the work each simulated runner accepts per request
+    #[arg(long)]
+    simulate_max_work: Option<i32>,
+}
 
 fn main() {
     let args = Args::parse();
@@ -48,9 +135,26 @@ fn main() {
         args.max_loop_delay,
         args.delay_start_seconds,
         args.spawn_rate,
-        true,
+        args.debug,
     );
-    scenario_manager.get_python_scenario(args.scenario_spec.to_string()).unwrap();
+    scenario_manager.set_allocation(args.allocation, args.allocation_seed);
+    scenario_manager.set_lookahead(args.lookahead_periods);
+    if let Some(max_duration) = args.max_duration {
+        scenario_manager.set_max_duration(max_duration);
+    }
+    if let Some(start_at) = args.start_at {
+        scenario_manager.set_start_at(start_at);
+    }
+    if args.volume_multiplier != 1.0 {
+        scenario_manager
+            .set_multiplier(None, args.volume_multiplier)
+            .unwrap();
+    }
+    if let Some(spawn_burst) = args.spawn_burst {
+        scenario_manager.set_spawn_burst(spawn_burst);
+    }
+    let source = scenario_source::from_spec(&args.scenario_spec).unwrap();
+    scenario_manager.load_spec(source.as_ref()).unwrap();
 
     let mut controller = Controller::new(
         args.scenario_spec.to_string(),
@@ -59,5 +163,37 @@ fn main() {
         scenario_manager,
         args.debug,
     );
-    controller.run_server();
+    controller.set_hit_rate_window(args.hit_rate_window);
+    controller.set_exclude_paused_time(args.exclude_paused_time);
+    controller.set_abort_conditions(AbortConditions {
+        no_runners_seconds: args.abort_no_runners_seconds,
+        min_achieved_ratio: args.abort_below_achieved_percent.map(|percent| percent / 100.0),
+        underachieving_periods: args.abort_below_achieved_periods,
+        max_error_rate: args.abort_error_rate,
+    });
+    if let Err(e) = controller.watch_signals() {
+        eprintln!("Failed to register pause/resume signal handlers: {}", e);
+    }
+    if args.simulate_runners > 0 {
+        simulate(
+            &mut controller,
+            SimulationConfig {
+                runners: args.simulate_runners,
+                journey_latency: Duration::from_millis(args.simulate_latency_ms),
+                request_interval: Duration::from_millis(args.simulate_request_interval_ms),
+                report_interval: Duration::from_secs(args.simulate_report_seconds),
+                max_work: args.simulate_max_work,
+            },
+        );
+    } else {
+        controller.run_server();
+    }
+
+    let report = controller.report();
+    report.print();
+    if let Some(report_path) = args.report_path {
+        if let Err(e) = report.write(&report_path) {
+            eprintln!("Failed to write report to {}: {}", report_path, e);
+        }
+    }
 }
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::controller::controller::{
+    ControlCommand, ControlReply, Controller, HelloData, MessageData, Status,
+};
+use crate::controller::scenario_manager::Work;
+use crate::controller::transport::{ChannelClient, ChannelTransport};
+
+/// How the fake runners behave during a simulated run.
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    pub runners: usize,
+    /// How long each journey "executes" for.
+    pub journey_latency: Duration,
+    /// How long a runner waits between request_work calls.
+    pub request_interval: Duration,
+    /// How often required and actual volumes are sampled.
+    pub report_interval: Duration,
+    pub max_work: Option<i32>,
+}
+
+/// A runner that speaks the hello/request_work protocol but only sleeps.
+struct SimulatedRunner {
+    client: ChannelClient,
+    identity: String,
+    config: SimulationConfig,
+    runner_id: i32,
+    running: Vec<(Instant, i32)>,
+}
+
+impl SimulatedRunner {
+    fn new(client: ChannelClient, identity: String, config: SimulationConfig) -> Self {
+        Self {
+            client,
+            identity,
+            config,
+            runner_id: 0,
+            running: Vec::new(),
+        }
+    }
+
+    fn hello(&mut self) -> Option<()> {
+        let hello_data = HelloData {
+            identity: Some(self.identity.clone()),
+            reductions: true,
+            ..Default::default()
+        };
+        let msg = rmp_serde::to_vec_named(&(1, hello_data)).unwrap();
+        let reply = self.client.request(msg)?;
+        let (runner_id, _, _): (i32, String, HashMap<String, String>) =
+            rmp_serde::from_slice(&reply).ok()?;
+        self.runner_id = runner_id;
+        Some(())
+    }
+
+    fn current_work(&mut self) -> HashMap<i32, i32> {
+        let now = Instant::now();
+        self.running.retain(|(done_at, _)| *done_at > now);
+        let mut current_work = HashMap::new();
+        for (_, scenario_id) in self.running.iter() {
+            *current_work.entry(*scenario_id).or_insert(0) += 1;
+        }
+        current_work
+    }
+
+    /// Returns whether the controller asked the runner to stop.
+    fn request_work(&mut self) -> Option<bool> {
+        let message = MessageData {
+            runner_id: self.runner_id,
+            current_work: self.current_work(),
+            completed_data_ids: vec![],
+            max_work: self.config.max_work,
+            errors: 0,
+        };
+        let msg = rmp_serde::to_vec_named(&(2, message)).unwrap();
+        let reply = self.client.request(msg)?;
+        let (work, _, stop, reductions): (Work, HashMap<String, String>, bool, HashMap<i32, i32>) =
+            rmp_serde::from_slice(&reply).ok()?;
+
+        for (scenario_id, mut count) in reductions {
+            self.running.retain(|(_, running_id)| {
+                if count > 0 && *running_id == scenario_id {
+                    count -= 1;
+                    return false;
+                }
+                true
+            });
+        }
+
+        let done_at = Instant::now() + self.config.journey_latency;
+        for (scenario_id, _, _, _) in work {
+            self.running.push((done_at, scenario_id));
+        }
+        Some(stop)
+    }
+
+    fn bye(&mut self) {
+        let message = MessageData {
+            runner_id: self.runner_id,
+            current_work: HashMap::new(),
+            completed_data_ids: vec![],
+            max_work: None,
+            errors: 0,
+        };
+        let msg = rmp_serde::to_vec_named(&(3, message)).unwrap();
+        self.client.request(msg);
+    }
+
+    fn run(mut self) {
+        if self.hello().is_none() {
+            eprintln!("Simulated runner failed to say hello");
+            return;
+        }
+        while let Some(stop) = self.request_work() {
+            if stop {
+                break;
+            }
+            thread::sleep(self.config.request_interval);
+        }
+        self.bye();
+    }
+}
+
+fn request_status(client: &ChannelClient) -> Option<Status> {
+    let msg = rmp_serde::to_vec_named(&(4, ControlCommand::Status)).unwrap();
+    let reply = client.request(msg)?;
+    match rmp_serde::from_slice(&reply).ok()? {
+        ControlReply::Status(status) => Some(status),
+        ControlReply::Error { message } => {
+            eprintln!("Status request failed: {}", message);
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Samples required against actual volumes until the scenario finishes.
+fn report(client: ChannelClient, interval: Duration) {
+    // per scenario: (samples, sum of actual / required)
+    let mut tracking: HashMap<i32, (u64, f64)> = HashMap::new();
+    let mut spawn = None;
+
+    println!("time\tscenario\trequired\tactual\trunners");
+    while let Some(status) = request_status(&client) {
+        if let Some(starts_in) = status.starts_in {
+            println!("waiting, starts in {}s", starts_in);
+        }
+        let mut scenario_ids: Vec<&i32> = status.required.keys().collect();
+        scenario_ids.sort();
+        for scenario_id in scenario_ids {
+            let required = status.required[scenario_id];
+            let actual = *status.actual.get(scenario_id).unwrap_or(&0);
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                status.scenario_time, scenario_id, required, actual, status.active_runners
+            );
+            if required > 0 {
+                let entry = tracking.entry(*scenario_id).or_insert((0, 0.0));
+                entry.0 += 1;
+                entry.1 += actual as f64 / required as f64;
+            }
+        }
+        spawn = Some(status.spawn);
+        if !status.active {
+            break;
+        }
+        thread::sleep(interval);
+    }
+
+    if let Some(spawn) = spawn {
+        println!(
+            "spawned {} throttled requests {} (rate {}/s burst {})",
+            spawn.spawned, spawn.throttled_requests, spawn.rate, spawn.burst
+        );
+    }
+
+    println!("scenario\tmean actual/required");
+    let mut scenario_ids: Vec<&i32> = tracking.keys().collect();
+    scenario_ids.sort();
+    for scenario_id in scenario_ids {
+        let (samples, total) = tracking[scenario_id];
+        println!("{}\t{:.3}", scenario_id, total / samples as f64);
+    }
+}
+
+/// Run `controller` against `config.runners` simulated runners in this process.
+/// Returns once the scenario has finished and every runner has said bye.
+pub fn simulate(controller: &mut Controller, config: SimulationConfig) {
+    let (mut transport, client) = ChannelTransport::pair();
+
+    let mut handles = Vec::new();
+    for index in 0..config.runners {
+        let identity = format!("simulated-{}", index);
+        let runner = SimulatedRunner::new(client.clone(), identity, config.clone());
+        handles.push(thread::spawn(move || runner.run()));
+    }
+    let reporter_client = client.clone();
+    let report_interval = config.report_interval;
+    handles.push(thread::spawn(move || {
+        report(reporter_client, report_interval)
+    }));
+    // only the runners and the reporter keep the transport open
+    drop(client);
+
+    controller.serve(&mut transport);
+    // unblocks anything still waiting on a reply
+    drop(transport);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}